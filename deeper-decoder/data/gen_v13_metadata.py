@@ -0,0 +1,138 @@
+# Writes v13_metadata_test.scale, a metadata v13 with the System, Balances, Credit and Staking
+# modules of the deeper-chain runtime before the v14 upgrade (spec < 7), for the legacy decoder
+# tests. Only the storage entries and events read by the decoders are kept.
+#
+#   python3 gen_v13_metadata.py
+
+import os
+
+
+def compact(n):
+    if n < 1 << 6:
+        return bytes([n << 2])
+    if n < 1 << 14:
+        return ((n << 2) | 1).to_bytes(2, "little")
+    if n < 1 << 30:
+        return ((n << 2) | 2).to_bytes(4, "little")
+    raw = n.to_bytes((n.bit_length() + 7) // 8, "little")
+    return bytes([((len(raw) - 4) << 2) | 3]) + raw
+
+
+def string(s):
+    raw = s.encode()
+    return compact(len(raw)) + raw
+
+
+def vec(items, encode):
+    return compact(len(items)) + b"".join(encode(item) for item in items)
+
+
+def option(value, encode):
+    return b"\x00" if value is None else b"\x01" + encode(value)
+
+
+BLAKE2_128_CONCAT = 2
+
+OPTIONAL = 0
+DEFAULT = 1
+
+
+def plain(name, ty, default=b"\x00"):
+    return string(name) + bytes([DEFAULT]) + b"\x00" + string(ty) + vec(default, lambda b: bytes([b])) + vec([], string)
+
+
+def map_entry(name, key, value):
+    ty = b"\x01" + bytes([BLAKE2_128_CONCAT]) + string(key) + string(value) + b"\x00"
+    return string(name) + bytes([OPTIONAL]) + ty + vec(b"\x00", lambda b: bytes([b])) + vec([], string)
+
+
+def event(name, args):
+    return string(name) + vec(args, string) + vec([], string)
+
+
+def module(name, index, storage=None, events=None):
+    storage = None if storage is None else string(name) + vec(storage, lambda entry: entry)
+    return (
+        string(name)
+        + option(storage, lambda s: s)
+        + option(None, lambda c: c)
+        + option(events, lambda es: vec(es, lambda e: e))
+        + vec([], lambda c: c)
+        + vec([], lambda e: e)
+        + bytes([index])
+    )
+
+
+modules = [
+    module(
+        "System",
+        0,
+        storage=[
+            map_entry("Account", "T::AccountId", "AccountInfo<T::Index, T::AccountData>"),
+            plain("Number", "T::BlockNumber", b"\x00\x00\x00\x00"),
+            plain("Events", "Vec<EventRecord<T::Event, T::Hash>>"),
+        ],
+        events=[
+            event("ExtrinsicSuccess", ["DispatchInfo"]),
+            event("ExtrinsicFailed", ["DispatchError", "DispatchInfo"]),
+            event("CodeUpdated", []),
+            event("NewAccount", ["AccountId"]),
+            event("KilledAccount", ["AccountId"]),
+            event("Remarked", ["AccountId", "Hash"]),
+        ],
+    ),
+    module(
+        "Balances",
+        5,
+        events=[
+            event("Endowed", ["AccountId", "Balance"]),
+            event("DustLost", ["AccountId", "Balance"]),
+            event("Transfer", ["AccountId", "AccountId", "Balance"]),
+            event("BalanceSet", ["AccountId", "Balance", "Balance"]),
+            event("Deposit", ["AccountId", "Balance"]),
+            event("Reserved", ["AccountId", "Balance"]),
+            event("Unreserved", ["AccountId", "Balance"]),
+        ],
+    ),
+    module(
+        "Staking",
+        7,
+        storage=[map_entry("Delegators", "T::AccountId", "DelegatorData")],
+        events=[
+            event("EraPayout", ["EraIndex", "Balance", "Balance"]),
+            event("Delegated", ["AccountId", "Vec<AccountId>"]),
+            event("UnDelegated", ["AccountId"]),
+        ],
+    ),
+    module(
+        "Credit",
+        20,
+        storage=[map_entry("UserCredit", "T::AccountId", "CreditData")],
+        events=[
+            event("CreditUpdateSuccess", ["AccountId", "u64"]),
+            event("CreditUpdateFailed", ["AccountId", "u64"]),
+            event("CreditSettingUpdated", ["CreditSetting"]),
+            event("CreditDataAdded", ["AccountId", "CreditData"]),
+            event("CreditDataUpdated", ["AccountId", "CreditData"]),
+        ],
+    ),
+]
+
+extrinsic = bytes([4]) + vec(
+    [
+        "CheckSpecVersion",
+        "CheckTxVersion",
+        "CheckGenesis",
+        "CheckMortality",
+        "CheckNonce",
+        "CheckWeight",
+        "ChargeTransactionPayment",
+    ],
+    string,
+)
+
+metadata = b"meta" + bytes([13]) + vec(modules, lambda m: m) + extrinsic
+
+path = os.path.join(os.path.dirname(os.path.abspath(__file__)), "v13_metadata_test.scale")
+with open(path, "wb") as f:
+    f.write(metadata)
@@ -1,557 +1,201 @@
-use desub_current::value::{Composite, Value};
-use sp_core::crypto::AccountId32;
-use sp_runtime::MultiAddress;
-use std::collections::HashSet;
+use async_trait::async_trait;
+use sp_core::crypto::Ss58Codec;
+use sqlx::postgres::Postgres;
+use sqlx::types::Decimal;
+use sqlx::Transaction;
 
-pub fn get_balance_changed_account_ids(ext: &str) -> HashSet<AccountId32> {
-    let mut account_ids: HashSet<AccountId32> = HashSet::new();
+use crate::common::BlockMetadata;
+use crate::error::{DecodeError, DecodeFailure};
+use crate::pallet_decoder::{Block, PalletDecoder};
+use crate::value_ext::ValueExt;
 
-    match serde_json::from_str::<Vec<crate::CurrentExtrinsic>>(ext) {
-        Ok(extrinsics) => {
-            for extrinsic in &extrinsics {
-                if extrinsic.current.call_data.pallet_name == "Balances"
-                    && ["transfer", "transfer_keep_alive", "transfer_all"]
-                        .contains(&extrinsic.current.call_data.ty.name().as_str())
-                {
-                    if extrinsic.current.call_data.arguments.len() > 1 {
-                        match extrinsic.current.call_data.arguments[0].clone() {
-                            Value::Composite(Composite::Named(cn)) => {
-                                // println!("cn, len: {}, val: {:?}", cn.len(), cn[1]);
-                                match cn[1].1.clone() {
-                                    Value::Composite(Composite::Unnamed(cn1)) => {
-                                        // println!("cn1 {:?}, {}", cn1[0], cn1.len());
-                                        match cn1[0].clone() {
-                                            Value::Composite(Composite::Unnamed(cn2)) => {
-                                                // println!("cn2 {:?}", cn2);
-                                                match cn2[0].clone() {
-                                                    Value::Composite(Composite::Unnamed(cn3)) => {
-                                                        match crate::common::decode_account_id(cn3)
-                                                        {
-                                                            Ok(account) => {
-                                                                account_ids.insert(account);
-                                                            }
-                                                            _ => {} // ignore error
-                                                        }
-                                                    }
-                                                    _ => {}
-                                                }
-                                            }
-                                            _ => {}
-                                        }
-                                    }
-                                    _ => {}
-                                };
-                            }
-                            _ => {}
-                        };
-                    }
-                }
+/// Hex prefix of the `System.Account` storage keys.
+pub fn system_account_prefix() -> String {
+    hex::encode(crate::common::storage_prefix("System", "Account"))
+}
+
+/// Decodes a `System.Account` storage value into (nonce, free, reserved, misc_frozen, fee_frozen).
+pub fn decode_account_info(
+    storage_key: &str,
+    storage_val: &str,
+    meta: &BlockMetadata,
+) -> Result<(u32, u128, u128, u128, u128), DecodeError> {
+    let info = crate::common::decode_storage(storage_key, storage_val, meta)?;
+    let data = info.field("data")?;
+
+    Ok((
+        info.field("nonce")?.as_u32()?,
+        data.field("free")?.as_u128()?,
+        data.field("reserved")?.as_u128()?,
+        data.field("misc_frozen")?.as_u128()?,
+        data.field("fee_frozen")?.as_u128()?,
+    ))
+}
+
+/// Writes the balances of the accounts into `block_balance`.
+pub struct BalanceDecoder;
 
-                match extrinsic.current.signature.clone() {
-                    Some(signature_val) => match signature_val.address {
-                        MultiAddress::Id(account_id) => {
-                            account_ids.insert(account_id);
-                        }
-                        _ => {}
-                    },
-                    _ => {}
+#[async_trait]
+impl PalletDecoder for BalanceDecoder {
+    /// (block_num, address, nonce, free, reserved, misc_frozen, fee_frozen)
+    type Row = (i32, String, u32, u128, u128, u128, u128);
+
+    fn name(&self) -> &'static str {
+        "balance"
+    }
+
+    // every account whose balance changed in a block has its `System.Account` entry in the
+    // block storage, whatever changed it (transfers, fees, rewards, evm, sudo)
+    fn storage_prefixes(&self) -> Vec<String> {
+        vec![system_account_prefix()]
+    }
+
+    fn decode(&self, block: &Block, rows: &mut Vec<Self::Row>, failures: &mut Vec<DecodeFailure>) {
+        let block_num = block.block_num();
+        for (storage_key, storage_str) in block.storage() {
+            let addr = match crate::common::map_key_account_id(
+                storage_key,
+                "System",
+                "Account",
+                block.meta(),
+            ) {
+                Ok(addr) => addr,
+                Err(err) => {
+                    failures.push(DecodeFailure::storage(
+                        block_num,
+                        storage_key,
+                        storage_str,
+                        err,
+                    ));
+                    continue;
+                }
+            };
+            if storage_str.is_empty() {
+                // the entry was removed, the account is reaped
+                rows.push((block_num, addr.to_ss58check(), 0, 0, 0, 0, 0));
+                continue;
+            }
+            match decode_account_info(storage_key, storage_str, block.meta()) {
+                Ok((nonce, free, reserved, misc_frozen, fee_frozen)) => {
+                    rows.push((
+                        block_num,
+                        addr.to_ss58check(),
+                        nonce,
+                        free,
+                        reserved,
+                        misc_frozen,
+                        fee_frozen,
+                    ));
+                }
+                Err(err) => {
+                    failures.push(DecodeFailure::storage(
+                        block_num,
+                        storage_key,
+                        storage_str,
+                        err,
+                    ));
                 }
             }
-            return account_ids;
-        }
-        Err(_) => {
-            return account_ids;
         }
     }
+
+    async fn write(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        rows: Vec<Self::Row>,
+    ) -> Result<(), sqlx::Error> {
+        let mut to_insert_block_nums: Vec<i32> = vec![];
+        let mut to_insert_addrs: Vec<String> = vec![];
+        let mut to_insert_nonces: Vec<u32> = vec![];
+        let mut to_insert_fee = vec![];
+        let mut to_insert_reserved = vec![];
+        let mut to_insert_misc_frozen = vec![];
+        let mut to_insert_fee_frozen = vec![];
+        rows.into_iter().for_each(|value| {
+            to_insert_block_nums.push(value.0);
+            to_insert_addrs.push(value.1);
+            to_insert_nonces.push(value.2);
+            to_insert_fee.push(Decimal::from_i128_with_scale(value.3 as i128, 0));
+            to_insert_reserved.push(Decimal::from_i128_with_scale(value.4 as i128, 0));
+            to_insert_misc_frozen.push(Decimal::from_i128_with_scale(value.5 as i128, 0));
+            to_insert_fee_frozen.push(Decimal::from_i128_with_scale(value.6 as i128, 0));
+        });
+        sqlx::query(
+            "insert into block_balance(block_num, address, nonce, free, reserved, misc_frozen, fee_frozen) select * from unnest ($1, $2, $3, $4, $5, $6, $7) on conflict (block_num, address) do update set nonce = excluded.nonce, free = excluded.free, reserved = excluded.reserved, misc_frozen = excluded.misc_frozen, fee_frozen = excluded.fee_frozen;",
+        )
+        .bind(&to_insert_block_nums)
+        .bind(&to_insert_addrs)
+        .bind(&to_insert_nonces)
+        .bind(&to_insert_fee)
+        .bind(&to_insert_reserved)
+        .bind(&to_insert_misc_frozen)
+        .bind(&to_insert_fee_frozen)
+        .execute(&mut *tx)
+        .await?;
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use sp_core::crypto::Ss58Codec;
+    use sp_core::crypto::AccountId32;
 
     use super::*;
 
     #[test]
-    fn test_transfer() {
-        let s = r##"[
-            {
-              "Current": {
-                "call_data": {
-                  "ty": {
-                    "docs": [
-                      "Set the current time.",
-                      "",
-                      "This call should be invoked exactly once per block. It will panic at the finalization",
-                      "phase, if this call hasn't been invoked by that time.",
-                      "",
-                      "The timestamp should be greater than the previous one by the amount specified by",
-                      "`MinimumPeriod`.",
-                      "",
-                      "The dispatch origin for this call must be `Inherent`.",
-                      "",
-                      "# <weight>",
-                      "- `O(1)` (Note that implementations of `OnTimestampSet` must also be `O(1)`)",
-                      "- 1 storage read and 1 storage mutation (codec `O(1)`). (because of `DidUpdate::take` in",
-                      "  `on_finalize`)",
-                      "- 1 event handler `on_timestamp_set`. Must be `O(1)`.",
-                      "# </weight>"
-                    ],
-                    "name": "set",
-                    "index": 0,
-                    "fields": [
-                      {
-                        "name": "now",
-                        "type": 152,
-                        "typeName": "T::Moment"
-                      }
-                    ]
-                  },
-                  "arguments": [
-                    1649339555002
-                  ],
-                  "pallet_name": "Timestamp"
-                },
-                "signature": null
-              }
-            },
-            {
-              "Current": {
-                "call_data": {
-                  "ty": {
-                    "name": "note_min_gas_price_target",
-                    "index": 0,
-                    "fields": [
-                      {
-                        "name": "target",
-                        "type": 106,
-                        "typeName": "U256"
-                      }
-                    ]
-                  },
-                  "arguments": [
-                    [
-                      [
-                        1,
-                        0,
-                        0,
-                        0
-                      ]
-                    ]
-                  ],
-                  "pallet_name": "DynamicFee"
-                },
-                "signature": null
-              }
-            },
-            {
-              "Current": {
-                "call_data": {
-                  "ty": {
-                    "docs": [
-                      "Transfer some liquid free balance to another account.",
-                      "",
-                      "`transfer` will set the `FreeBalance` of the sender and receiver.",
-                      "If the sender's account is below the existential deposit as a result",
-                      "of the transfer, the account will be reaped.",
-                      "",
-                      "The dispatch origin for this call must be `Signed` by the transactor.",
-                      "",
-                      "# <weight>",
-                      "- Dependent on arguments but not critical, given proper implementations for input config",
-                      "  types. See related functions below.",
-                      "- It contains a limited number of reads and writes internally and no complex",
-                      "  computation.",
-                      "",
-                      "Related functions:",
-                      "",
-                      "  - `ensure_can_withdraw` is always called internally but has a bounded complexity.",
-                      "  - Transferring balances to accounts that did not exist before will cause",
-                      "    `T::OnNewAccount::on_new_account` to be called.",
-                      "  - Removing enough funds from an account will trigger `T::DustRemoval::on_unbalanced`.",
-                      "  - `transfer_keep_alive` works the same way as `transfer`, but has an additional check",
-                      "    that the transfer will not kill the origin account.",
-                      "---------------------------------",
-                      "- Origin account is already in memory, so no DB operations for them.",
-                      "# </weight>"
-                    ],
-                    "name": "transfer",
-                    "index": 0,
-                    "fields": [
-                      {
-                        "name": "dest",
-                        "type": 155,
-                        "typeName": "<T::Lookup as StaticLookup>::Source"
-                      },
-                      {
-                        "name": "value",
-                        "type": 53,
-                        "typeName": "T::Balance"
-                      }
-                    ]
-                  },
-                  "arguments": [
-                    {
-                      "name": "Id",
-                      "values": [
-                        [
-                          [
-                            168,
-                            139,
-                            89,
-                            175,
-                            231,
-                            63,
-                            14,
-                            118,
-                            158,
-                            79,
-                            157,
-                            133,
-                            205,
-                            64,
-                            253,
-                            19,
-                            240,
-                            135,
-                            68,
-                            70,
-                            242,
-                            45,
-                            42,
-                            182,
-                            120,
-                            15,
-                            156,
-                            184,
-                            144,
-                            89,
-                            48,
-                            126
-                          ]
-                        ]
-                      ]
-                    },
-                    11110000000000
-                  ],
-                  "pallet_name": "Balances"
-                },
-                "signature": {
-                  "address": {
-                    "Id": "5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY"
-                  },
-                  "signature": {
-                    "Sr25519": "08b5ad39e23c1fb31d0ae0b75e5257a3a0d05fb004a0dd3930ad5ab9a258880f4389f5ea6c15da8ecf35f1d0742568e00938bd47d3efd0c60104972960f03483"
-                  },
-                  "extensions": [
-                    [
-                      "CheckNonZeroSender",
-                      []
-                    ],
-                    [
-                      "CheckSpecVersion",
-                      []
-                    ],
-                    [
-                      "CheckTxVersion",
-                      []
-                    ],
-                    [
-                      "CheckGenesis",
-                      []
-                    ],
-                    [
-                      "CheckMortality",
-                      [
-                        {
-                          "name": "Mortal246",
-                          "values": [
-                            6
-                          ]
-                        }
-                      ]
-                    ],
-                    [
-                      "CheckNonce",
-                      [
-                        2
-                      ]
-                    ],
-                    [
-                      "CheckWeight",
-                      []
-                    ],
-                    [
-                      "ChargeTransactionPayment",
-                      [
-                        0
-                      ]
-                    ]
-                  ]
-                }
-              }
-            }
-          ]"##;
-        let account_ids = get_balance_changed_account_ids(s);
-        let dest = AccountId32::from_ss58check("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
+    fn test_account_id_from_key() {
+        let addr = AccountId32::from_ss58check("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
             .unwrap();
+        let storage_key = hex::encode(crate::common::system_account_key(addr.clone()));
 
-        assert!(account_ids.contains(&dest));
+        assert!(storage_key.starts_with(&system_account_prefix()));
+        assert_eq!(
+            crate::common::map_key_account_id(
+                &storage_key,
+                "System",
+                "Account",
+                &crate::common::deeper_metadata()
+            ),
+            Ok(addr)
+        );
     }
 
     #[test]
-    fn test_transfer_keep_alive() {
-        let s = r##"[
-          {
-            "Current": {
-              "call_data": {
-                "ty": {
-                  "docs": [
-                    "Set the current time.",
-                    "",
-                    "This call should be invoked exactly once per block. It will panic at the finalization",
-                    "phase, if this call hasn't been invoked by that time.",
-                    "",
-                    "The timestamp should be greater than the previous one by the amount specified by",
-                    "`MinimumPeriod`.",
-                    "",
-                    "The dispatch origin for this call must be `Inherent`.",
-                    "",
-                    "# <weight>",
-                    "- `O(1)` (Note that implementations of `OnTimestampSet` must also be `O(1)`)",
-                    "- 1 storage read and 1 storage mutation (codec `O(1)`). (because of `DidUpdate::take` in",
-                    "  `on_finalize`)",
-                    "- 1 event handler `on_timestamp_set`. Must be `O(1)`.",
-                    "# </weight>"
-                  ],
-                  "name": "set",
-                  "index": 0,
-                  "fields": [
-                    {
-                      "name": "now",
-                      "type": 126,
-                      "typeName": "T::Moment"
-                    }
-                  ]
-                },
-                "arguments": [
-                  1639722390001
-                ],
-                "pallet_name": "Timestamp"
-              },
-              "signature": null
-            }
-          },
-          {
-            "Current": {
-              "call_data": {
-                "ty": {
-                  "docs": [
-                    "Same as the [`transfer`] call, but with a check that the transfer will not kill the",
-                    "origin account.",
-                    "",
-                    "99% of the time you want [`transfer`] instead.",
-                    "",
-                    "[`transfer`]: struct.Pallet.html#method.transfer",
-                    "# <weight>",
-                    "- Cheaper than transfer because account cannot be killed.",
-                    "- Base Weight: 51.4 µs",
-                    "- DB Weight: 1 Read and 1 Write to dest (sender is in overlay already)",
-                    "#</weight>"
-                  ],
-                  "name": "transfer_keep_alive",
-                  "index": 3,
-                  "fields": [
-                    {
-                      "name": "dest",
-                      "type": 131,
-                      "typeName": "<T::Lookup as StaticLookup>::Source"
-                    },
-                    {
-                      "name": "value",
-                      "type": 66,
-                      "typeName": "T::Balance"
-                    }
-                  ]
-                },
-                "arguments": [
-                  {
-                    "name": "Id",
-                    "values": [
-                      [
-                        [
-                          56,
-                          243,
-                          222,
-                          30,
-                          247,
-                          37,
-                          146,
-                          39,
-                          200,
-                          98,
-                          97,
-                          55,
-                          28,
-                          130,
-                          91,
-                          139,
-                          193,
-                          6,
-                          244,
-                          36,
-                          44,
-                          242,
-                          75,
-                          40,
-                          210,
-                          142,
-                          131,
-                          30,
-                          244,
-                          180,
-                          142,
-                          76
-                        ]
-                      ]
-                    ]
-                  },
-                  50000000000000000000
-                ],
-                "pallet_name": "Balances"
-              },
-              "signature": {
-                "address": {
-                  "Id": "5CHu6tEdZWEnGHa928e9CfsXnL5otzRg4xGwqCscXDrcH38t"
-                },
-                "signature": {
-                  "Sr25519": "6ea65968e3da6c526934007ca9cddc012ba59f4554a00742ac07e5b95e4828580de223d3ff02efe74f4f5c05753eaa3d25fe0e2567348428a99ecb4bf925898f"
-                },
-                "extensions": [
-                  [
-                    "CheckSpecVersion",
-                    []
-                  ],
-                  [
-                    "CheckTxVersion",
-                    []
-                  ],
-                  [
-                    "CheckGenesis",
-                    []
-                  ],
-                  [
-                    "CheckMortality",
-                    [
-                      {
-                        "name": "Mortal134",
-                        "values": [
-                          0
-                        ]
-                      }
-                    ]
-                  ],
-                  [
-                    "CheckNonce",
-                    [
-                      8
-                    ]
-                  ],
-                  [
-                    "CheckWeight",
-                    []
-                  ],
-                  [
-                    "ChargeTransactionPayment",
-                    [
-                      0
-                    ]
-                  ]
-                ]
-              }
-            }
-          }
-        ]"##;
-        let account_ids = get_balance_changed_account_ids(s);
-        let arr: Vec<AccountId32> = account_ids.iter().cloned().collect();
-        println!("{}, {}", arr[0].to_ss58check(), arr[1].to_ss58check());
-        let exp = AccountId32::from_ss58check("5DMNywVCTpuVUkoiitr5fYc9QT8ses5Rk4vTyQjfA5Lma5dr")
+    fn test_decode_account_info() {
+        let addr = AccountId32::from_ss58check("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
             .unwrap();
+        let storage_key = hex::encode(crate::common::system_account_key(addr));
+        // nonce 2, consumers 0, providers 1, sufficients 0, free 1000, reserved 10, frozen 0
+        let storage_val = format!(
+            "{}{}{}{}{}{}{}{}",
+            "02000000",
+            "00000000",
+            "01000000",
+            "00000000",
+            "e8030000000000000000000000000000",
+            "0a000000000000000000000000000000",
+            "00000000000000000000000000000000",
+            "00000000000000000000000000000000"
+        );
+        let info = decode_account_info(
+            &storage_key,
+            &storage_val,
+            &crate::common::deeper_metadata(),
+        )
+        .unwrap();
 
-        let alice = AccountId32::from_ss58check("5CHu6tEdZWEnGHa928e9CfsXnL5otzRg4xGwqCscXDrcH38t")
-            .unwrap();
-
-        assert!(account_ids.contains(&exp));
-        assert!(account_ids.contains(&alice));
-        assert_eq!(2, account_ids.len());
+        assert_eq!(info, (2, 1000, 10, 0, 0));
     }
 
     #[test]
-    fn test_legacy_extrinsics() {
-        let s = r##"[
-        {
-          "Legacy": {
-            "call": {
-              "args": [
-                {
-                  "arg": 1639713065000,
-                  "name": "now"
-                }
-              ],
-              "name": "set",
-              "module": "Timestamp"
-            },
-            "signature": null
-          }
-        },
-        {
-          "Legacy": {
-            "call": {
-              "args": [
-                {
-                  "arg": [
-                    [
-                      "code",
-                      "0x0061736d01000..."
-                    ]
-                  ],
-                  "name": "call"
-                },
-                {
-                  "arg": 1000000,
-                  "name": "_weight"
-                }
-              ],
-              "name": "set_code",
-              "module": "System"
-            },
-            "signature": {
-              "extra": [
-                null,
-                null,
-                null,
-                {
-                  "Mortal": [
-                    128,
-                    119
-                  ]
-                },
-                0,
-                null,
-                0
-              ],
-              "address": "5CHu6tEdZWEnGHa928e9CfsXnL5otzRg4xGwqCscXDrcH38t",
-              "signature": {
-                "name": "Sr25519",
-                "value": "0xf2bc2aa7be0920a938aafa71b290e3a74f8d9bc7ab23aa51c5627fd8340a2556e9a027aec3e70773bf57d0585895cdb98764cd826f2e8a4f28c39cfbac50488d"
-              }
-            }
-          }
-        }
-      ]"##;
-
-        let account_ids = get_balance_changed_account_ids(s);
+    fn test_decode_account_info_bad_value() {
+        let addr = AccountId32::from_ss58check("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
+            .unwrap();
+        let storage_key = hex::encode(crate::common::system_account_key(addr));
 
-        // this block is upgrade to spec 7, the set_code extrinsics is legacy, so can't be decoded
-        assert!(account_ids.is_empty());
+        assert!(
+            decode_account_info(&storage_key, "0200", &crate::common::deeper_metadata()).is_err()
+        );
     }
 }
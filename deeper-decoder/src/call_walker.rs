@@ -0,0 +1,639 @@
+use std::collections::HashMap;
+
+use codec::Encode;
+use desub_current::value::{Composite, Value};
+use scale_info::PortableRegistry;
+use sp_core::crypto::AccountId32;
+use sp_runtime::MultiAddress;
+
+use crate::common::BlockMetadata;
+use crate::error::DecodeError;
+use crate::value_ext::ValueExt;
+
+// calls wrapped in `Utility.batch`, `Sudo.sudo`, `Proxy.proxy` or `Multisig.as_multi` are
+// arguments of the extrinsic call, decoded as `Value::Variant(pallet, [Variant(call, args)])`.
+// Decoders looking for a call walk the whole tree instead of the top level call only.
+
+// nested calls are decoded from untrusted input, the walk stops at this depth
+const MAX_DEPTH: usize = 16;
+
+/// Origin a call is dispatched with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin {
+    Signed(AccountId32),
+    Root,
+    /// inherents and unsigned extrinsics
+    None,
+}
+
+/// A call of an extrinsic, the extrinsic call itself or a nested one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call<'a> {
+    pub pallet: &'a str,
+    pub name: &'a str,
+    /// arguments with their field name (`None` for unnamed fields) and their type id (`None`
+    /// if the type is not known)
+    pub args: Vec<(Option<&'a str>, Option<u32>, &'a Value)>,
+    pub origin: Origin,
+    /// type registry of the metadata the call was decoded with
+    pub types: Option<&'a PortableRegistry>,
+}
+
+impl<'a> Call<'a> {
+    pub fn is_call(&self, pallet: &str, names: &[&str]) -> bool {
+        self.pallet == pallet && names.contains(&self.name)
+    }
+
+    pub fn arg(&self, name: &str) -> Option<&'a Value> {
+        self.typed_arg(name).map(|(_, value)| value)
+    }
+
+    /// The argument `name` with its type id.
+    pub fn typed_arg(&self, name: &str) -> Option<(Option<u32>, &'a Value)> {
+        self.args
+            .iter()
+            .find(|(arg_name, _, _)| *arg_name == Some(name))
+            .map(|(_, ty, value)| (*ty, *value))
+    }
+
+    /// Reads the argument `name` as an account id, by its type.
+    pub fn arg_account_id(&self, name: &str) -> Option<Result<AccountId32, DecodeError>> {
+        let (ty, value) = self.typed_arg(name)?;
+        Some(crate::common::field_account_id(self.types, ty, value))
+    }
+
+    pub fn signer(&self) -> Option<&AccountId32> {
+        match &self.origin {
+            Origin::Signed(account_id) => Some(account_id),
+            _ => None,
+        }
+    }
+}
+
+/// Account which signed the extrinsic, `None` for evm addresses which have no substrate account.
+pub fn extrinsic_signer(extrinsic: &crate::CurrentExtrinsic) -> Option<AccountId32> {
+    match &extrinsic.current.signature.as_ref()?.address {
+        MultiAddress::Id(account_id) => Some(account_id.clone()),
+        MultiAddress::Address32(bytes) => Some(AccountId32::new(*bytes)),
+        _ => None,
+    }
+}
+
+/// The extrinsic call and every call nested in it, depth first, each with the origin it is
+/// dispatched with. `executed_multisigs` are the multisig accounts which dispatched their
+/// call in the extrinsic, see `executed_multisigs`.
+pub fn extrinsic_calls<'a>(
+    extrinsic: &'a crate::CurrentExtrinsic,
+    meta: &'a BlockMetadata,
+    executed_multisigs: &[AccountId32],
+) -> Vec<Call<'a>> {
+    let call_data = &extrinsic.current.call_data;
+    let call = Call {
+        pallet: &call_data.pallet_name,
+        name: call_data.ty.name(),
+        args: call_data
+            .ty
+            .fields()
+            .iter()
+            .zip(&call_data.arguments)
+            .map(|(field, value)| {
+                (
+                    field.name().map(String::as_str),
+                    Some(field.ty().id()),
+                    value,
+                )
+            })
+            .collect(),
+        origin: match extrinsic_signer(extrinsic) {
+            Some(signer) => Origin::Signed(signer),
+            None => Origin::None,
+        },
+        types: meta.types(),
+    };
+    walk_calls(call, executed_multisigs)
+}
+
+/// Multisig accounts which dispatched their call, by the index of the extrinsic which
+/// reached the threshold, from the `Multisig.MultisigExecuted` events.
+pub fn executed_multisigs<'a>(
+    events: impl IntoIterator<Item = &'a Value>,
+    meta: &BlockMetadata,
+) -> HashMap<u32, Vec<AccountId32>> {
+    let mut executed: HashMap<u32, Vec<AccountId32>> = HashMap::new();
+    for record in events {
+        let index = match record
+            .field("phase")
+            .and_then(crate::event_decoder::event_phase)
+        {
+            Ok((_, Some(index))) => index,
+            _ => continue,
+        };
+        // `MultisigExecuted { approving, timepoint, multisig, call_hash, result }`
+        if let Some(("Multisig", "MultisigExecuted", fields)) =
+            crate::event_decoder::event_variant(record)
+        {
+            if let Some(Ok(multisig)) = fields.get(2).map(|multisig| {
+                crate::event_decoder::event_account_id(
+                    meta,
+                    "Multisig",
+                    "MultisigExecuted",
+                    2,
+                    multisig,
+                )
+            }) {
+                executed.entry(index).or_default().push(multisig);
+            }
+        }
+    }
+    executed
+}
+
+/// `call` and every call nested in it, depth first.
+pub fn walk_calls<'a>(call: Call<'a>, executed_multisigs: &[AccountId32]) -> Vec<Call<'a>> {
+    let mut calls = vec![];
+    walk(call, 0, executed_multisigs, &mut calls);
+    calls
+}
+
+fn walk<'a>(
+    call: Call<'a>,
+    depth: usize,
+    executed_multisigs: &[AccountId32],
+    calls: &mut Vec<Call<'a>>,
+) {
+    let nested = nested_calls(&call, executed_multisigs);
+    let types = call.types;
+    calls.push(call);
+    if depth >= MAX_DEPTH {
+        return;
+    }
+    for (origin, ty, value) in nested {
+        if let Some(call) = value_call(value, ty, types, origin) {
+            walk(call, depth + 1, executed_multisigs, calls);
+        }
+    }
+}
+
+// the calls wrapped by `call` with the origin they are dispatched with and their type id
+fn nested_calls<'a>(
+    call: &Call<'a>,
+    executed_multisigs: &[AccountId32],
+) -> Vec<(Origin, Option<u32>, &'a Value)> {
+    let origin = call.origin.clone();
+    let with_origin = |origin: Origin, arg: Option<(Option<u32>, &'a Value)>| match arg {
+        Some((ty, value)) => vec![(origin, ty, value)],
+        None => vec![],
+    };
+    match (call.pallet, call.name) {
+        ("Utility", "batch" | "batch_all" | "force_batch") => {
+            let (ty, calls) = match call.typed_arg("calls") {
+                Some(arg) => arg,
+                None => return vec![],
+            };
+            let item_ty = item_type(call.types, ty);
+            match calls.as_seq() {
+                Ok(calls) => calls
+                    .iter()
+                    .map(|value| (origin.clone(), item_ty, value))
+                    .collect(),
+                _ => vec![],
+            }
+        }
+        ("Utility", "as_derivative") => {
+            let index = call.arg("index").and_then(|index| index.as_u16().ok());
+            match (call.signer(), index) {
+                (Some(signer), Some(index)) => with_origin(
+                    Origin::Signed(derivative_account_id(signer, index)),
+                    call.typed_arg("call"),
+                ),
+                _ => vec![],
+            }
+        }
+        ("Sudo", "sudo" | "sudo_unchecked_weight") => {
+            with_origin(Origin::Root, call.typed_arg("call"))
+        }
+        ("Sudo", "sudo_as") => match call.arg_account_id("who") {
+            Some(Ok(who)) => with_origin(Origin::Signed(who), call.typed_arg("call")),
+            _ => vec![],
+        },
+        ("Proxy", "proxy" | "proxy_announced") => match call.arg_account_id("real") {
+            Some(Ok(real)) => with_origin(Origin::Signed(real), call.typed_arg("call")),
+            _ => vec![],
+        },
+        // the call is dispatched by the multisig account once the threshold is reached, with a
+        // `Multisig.MultisigExecuted` event. Below the threshold the call is only stored, it is
+        // not walked, same for an approval without the call data.
+        ("Multisig", "as_multi" | "as_multi_threshold_1") => {
+            let threshold = match call.name {
+                "as_multi" => call.arg("threshold").and_then(|t| t.as_u16().ok()),
+                _ => Some(1),
+            };
+            let others = call
+                .typed_arg("other_signatories")
+                .and_then(|(ty, others)| {
+                    let other_ty = item_type(call.types, ty);
+                    let others = others.as_seq().ok()?;
+                    Some(
+                        others
+                            .iter()
+                            .map(|other| {
+                                crate::common::field_account_id(call.types, other_ty, other)
+                            })
+                            .collect::<Result<Vec<_>, _>>(),
+                    )
+                });
+            match (call.signer(), threshold, others) {
+                (Some(signer), Some(threshold), Some(Ok(others))) => {
+                    let multisig = multi_account_id(signer, &others, threshold);
+                    // `as_multi_threshold_1` dispatches the call right away, without an event
+                    if call.name == "as_multi" && !executed_multisigs.contains(&multisig) {
+                        return vec![];
+                    }
+                    with_origin(Origin::Signed(multisig), call.typed_arg("call"))
+                }
+                _ => vec![],
+            }
+        }
+        _ => vec![],
+    }
+}
+
+// type id of the items of a `Vec<T>` argument
+fn item_type(types: Option<&PortableRegistry>, ty: Option<u32>) -> Option<u32> {
+    crate::common::item_type(types?, ty?)
+}
+
+// `Value::Variant(pallet, [Variant(call, args)])` of the `Call` type `ty`, possibly wrapped,
+// e.g. the `WrapperKeepOpaque<Call>` of `Multisig.as_multi` is decoded as `(len, call)`
+fn value_call<'a>(
+    value: &'a Value,
+    ty: Option<u32>,
+    types: Option<&'a PortableRegistry>,
+    origin: Origin,
+) -> Option<Call<'a>> {
+    match value {
+        Value::Variant(pallet) => match &pallet.values {
+            Composite::Unnamed(values) if values.len() == 1 => match &values[0] {
+                Value::Variant(call) => {
+                    // `Call` has one variant per pallet wrapping the `Call` enum of the pallet
+                    let arg_types = match (types, ty) {
+                        (Some(types), Some(ty)) => {
+                            crate::common::variant_field_types(types, ty, &pallet.name)
+                                .and_then(|tys| tys.first().copied())
+                                .and_then(|ty| {
+                                    crate::common::variant_field_types(types, ty, &call.name)
+                                })
+                        }
+                        _ => None,
+                    }
+                    .unwrap_or_default();
+                    let arg_ty = |index: usize| arg_types.get(index).copied();
+                    Some(Call {
+                        pallet: &pallet.name,
+                        name: &call.name,
+                        args: match &call.values {
+                            Composite::Named(values) => values
+                                .iter()
+                                .enumerate()
+                                .map(|(index, (name, value))| {
+                                    (Some(name.as_str()), arg_ty(index), value)
+                                })
+                                .collect(),
+                            Composite::Unnamed(values) => values
+                                .iter()
+                                .enumerate()
+                                .map(|(index, value)| (None, arg_ty(index), value))
+                                .collect(),
+                        },
+                        origin,
+                        types,
+                    })
+                }
+                _ => None,
+            },
+            _ => None,
+        },
+        Value::Composite(Composite::Unnamed(values)) => {
+            let last_ty = match (types, ty) {
+                (Some(types), Some(ty)) => {
+                    crate::common::field_types(types, ty).and_then(|tys| tys.last().copied())
+                }
+                _ => None,
+            };
+            value_call(values.last()?, last_ty, types, origin)
+        }
+        _ => None,
+    }
+}
+
+/// Account of `Multisig` for the signatories and the threshold, same as
+/// `pallet_multisig::Pallet::multi_account_id`.
+pub fn multi_account_id(
+    signer: &AccountId32,
+    others: &[AccountId32],
+    threshold: u16,
+) -> AccountId32 {
+    let mut signatories = others.to_vec();
+    signatories.push(signer.clone());
+    signatories.sort();
+    let entropy = (b"modlpy/utilisuba", signatories, threshold).encode();
+    AccountId32::new(sp_core::blake2_256(&entropy))
+}
+
+/// Account of `Utility.as_derivative`, same as `pallet_utility::Pallet::derivative_account_id`.
+pub fn derivative_account_id(signer: &AccountId32, index: u16) -> AccountId32 {
+    let entropy = (b"modlpy/utilisuba", signer, index).encode();
+    AccountId32::new(sp_core::blake2_256(&entropy))
+}
+
+#[cfg(test)]
+mod tests {
+    use desub_current::value::{Primitive, Variant};
+    use scale_info::TypeDef;
+    use sp_core::crypto::Ss58Codec;
+
+    use super::*;
+    use crate::common::deeper_metadata;
+    use crate::test_util::{account_value, bytes_value, event_record, variant};
+
+    fn account(addr: &str) -> AccountId32 {
+        AccountId32::from_ss58check(addr).unwrap()
+    }
+
+    // the arguments of `pallet.name` with their type id, from the `Call` enum of the runtime
+    fn typed_args<'a>(
+        types: &PortableRegistry,
+        pallet: &str,
+        name: &str,
+        args: Vec<(&'a str, &'a Value)>,
+    ) -> Vec<(Option<&'a str>, Option<u32>, &'a Value)> {
+        let runtime_call = types
+            .types()
+            .iter()
+            .find(|ty| ty.ty().path().segments().join("::") == "node_runtime::Call")
+            .unwrap()
+            .id();
+        let pallet_call =
+            crate::common::variant_field_types(types, runtime_call, pallet).unwrap()[0];
+        let fields = match types.resolve(pallet_call).unwrap().type_def() {
+            TypeDef::Variant(variants) => variants
+                .variants()
+                .iter()
+                .find(|def| def.name().as_str() == name)
+                .unwrap()
+                .fields(),
+            _ => unreachable!(),
+        };
+        args.into_iter()
+            .map(|(arg, value)| {
+                let ty = fields
+                    .iter()
+                    .find(|field| field.name().map(String::as_str) == Some(arg))
+                    .map(|field| field.ty().id());
+                assert!(ty.is_some(), "{}.{} has no argument {}", pallet, name, arg);
+                (Some(arg), ty, value)
+            })
+            .collect()
+    }
+
+    fn call_value(pallet: &str, name: &str, args: Vec<(&str, Value)>) -> Value {
+        Value::Variant(Variant {
+            name: pallet.to_string(),
+            values: Composite::Unnamed(vec![Value::Variant(Variant {
+                name: name.to_string(),
+                values: Composite::Named(
+                    args.into_iter()
+                        .map(|(name, value)| (name.to_string(), value))
+                        .collect(),
+                ),
+            })]),
+        })
+    }
+
+    fn delegate() -> Value {
+        call_value(
+            "Staking",
+            "delegate",
+            vec![("validators", Value::Composite(Composite::Unnamed(vec![])))],
+        )
+    }
+
+    #[test]
+    fn test_walk_batch_and_proxy() {
+        let signer = account("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ");
+        let real = account("5GNJqTPyNqANBkUVMN1LPPrxXnFouWXoe2wNSmmEoLctxiZY");
+        let proxy = call_value(
+            "Proxy",
+            "proxy",
+            vec![
+                ("real", account_value(&real)),
+                (
+                    "force_proxy_type",
+                    Value::Variant(Variant {
+                        name: String::from("None"),
+                        values: Composite::Unnamed(vec![]),
+                    }),
+                ),
+                ("call", delegate()),
+            ],
+        );
+        let calls = Value::Composite(Composite::Unnamed(vec![delegate(), proxy]));
+        let meta = deeper_metadata();
+        let types = meta.types().unwrap();
+        let batch = Call {
+            pallet: "Utility",
+            name: "batch_all",
+            args: typed_args(types, "Utility", "batch_all", vec![("calls", &calls)]),
+            origin: Origin::Signed(signer.clone()),
+            types: Some(types),
+        };
+
+        let delegates: Vec<Origin> = walk_calls(batch, &[])
+            .into_iter()
+            .filter(|call| call.is_call("Staking", &["delegate"]))
+            .map(|call| call.origin)
+            .collect();
+        assert_eq!(
+            delegates,
+            vec![Origin::Signed(signer), Origin::Signed(real)]
+        );
+    }
+
+    // (threshold, other_signatories, call) of a 2 of 2 `Multisig.as_multi` of `Sudo.sudo`
+    fn multisig_args(other: &AccountId32) -> (Value, Value, Value) {
+        let sudo = call_value("Sudo", "sudo", vec![("call", delegate())]);
+        // `WrapperKeepOpaque<Call>` is decoded as (encoded len, call)
+        let opaque = Value::Composite(Composite::Unnamed(vec![
+            Value::Primitive(Primitive::U32(40)),
+            sudo,
+        ]));
+        let others = Value::Composite(Composite::Unnamed(vec![account_value(other)]));
+        (Value::Primitive(Primitive::U16(2)), others, opaque)
+    }
+
+    #[test]
+    fn test_walk_sudo_and_multisig() {
+        let signer = account("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ");
+        let other = account("5GNJqTPyNqANBkUVMN1LPPrxXnFouWXoe2wNSmmEoLctxiZY");
+        let (threshold, others, opaque) = multisig_args(&other);
+        let meta = deeper_metadata();
+        let types = meta.types().unwrap();
+        let multisig = Call {
+            pallet: "Multisig",
+            name: "as_multi",
+            args: typed_args(
+                types,
+                "Multisig",
+                "as_multi",
+                vec![
+                    ("threshold", &threshold),
+                    ("other_signatories", &others),
+                    ("call", &opaque),
+                ],
+            ),
+            origin: Origin::Signed(signer.clone()),
+            types: Some(types),
+        };
+        let multi = multi_account_id(&signer, &[other], 2);
+
+        let calls = walk_calls(multisig, &[multi.clone()]);
+        let origins: Vec<(&str, &Origin)> =
+            calls.iter().map(|call| (call.name, &call.origin)).collect();
+        let multi = Origin::Signed(multi);
+        assert_eq!(
+            origins,
+            vec![
+                ("as_multi", &Origin::Signed(signer)),
+                ("sudo", &multi),
+                ("delegate", &Origin::Root),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_walk_multisig_below_threshold() {
+        let signer = account("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ");
+        let other = account("5GNJqTPyNqANBkUVMN1LPPrxXnFouWXoe2wNSmmEoLctxiZY");
+        let (threshold, others, opaque) = multisig_args(&other);
+        let meta = deeper_metadata();
+        let types = meta.types().unwrap();
+        let multisig = Call {
+            pallet: "Multisig",
+            name: "as_multi",
+            args: typed_args(
+                types,
+                "Multisig",
+                "as_multi",
+                vec![
+                    ("threshold", &threshold),
+                    ("other_signatories", &others),
+                    ("call", &opaque),
+                ],
+            ),
+            origin: Origin::Signed(signer.clone()),
+            types: Some(types),
+        };
+        // the first approval stores the call, another multisig of the extrinsic executed
+        let executed = vec![multi_account_id(&signer, &[other], 1)];
+
+        let calls = walk_calls(multisig, &executed);
+        let names: Vec<&str> = calls.iter().map(|call| call.name).collect();
+        assert_eq!(names, vec!["as_multi"]);
+    }
+
+    #[test]
+    fn test_walk_sudo_as() {
+        let signer = account("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ");
+        let who = account("5GNJqTPyNqANBkUVMN1LPPrxXnFouWXoe2wNSmmEoLctxiZY");
+        let meta = deeper_metadata();
+        let types = meta.types().unwrap();
+        let call = delegate();
+        // `who` is a `MultiAddress`, an evm address has no substrate account
+        let address = |name: &str, value: Value| variant(name, Composite::Unnamed(vec![value]));
+        let sudo_as = |who: &Value| {
+            walk_calls(
+                Call {
+                    pallet: "Sudo",
+                    name: "sudo_as",
+                    args: typed_args(
+                        types,
+                        "Sudo",
+                        "sudo_as",
+                        vec![("who", who), ("call", &call)],
+                    ),
+                    origin: Origin::Signed(signer.clone()),
+                    types: Some(types),
+                },
+                &[],
+            )
+            .into_iter()
+            .map(|call| (call.name.to_string(), call.origin))
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            sudo_as(&address("Id", account_value(&who))),
+            vec![
+                (String::from("sudo_as"), Origin::Signed(signer.clone())),
+                (String::from("delegate"), Origin::Signed(who)),
+            ]
+        );
+        assert_eq!(
+            sudo_as(&address("Address20", bytes_value(&[0x11; 20]))),
+            vec![(String::from("sudo_as"), Origin::Signed(signer))]
+        );
+    }
+
+    #[test]
+    fn test_executed_multisigs() {
+        let signer = account("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ");
+        let multi = account("5GNJqTPyNqANBkUVMN1LPPrxXnFouWXoe2wNSmmEoLctxiZY");
+        let executed = |multisig: &AccountId32| {
+            Composite::Named(vec![
+                (String::from("approving"), account_value(&signer)),
+                (
+                    String::from("timepoint"),
+                    Value::Composite(Composite::Unnamed(vec![])),
+                ),
+                (String::from("multisig"), account_value(multisig)),
+                (String::from("call_hash"), bytes_value(&[0; 32])),
+                (
+                    String::from("result"),
+                    variant("Ok", Composite::Unnamed(vec![])),
+                ),
+            ])
+        };
+        let events = vec![
+            event_record(1, "Multisig", "MultisigExecuted", executed(&multi)),
+            event_record(
+                2,
+                "Multisig",
+                "NewMultisig",
+                Composite::Named(vec![
+                    (String::from("approving"), account_value(&signer)),
+                    (String::from("multisig"), account_value(&signer)),
+                    (String::from("call_hash"), bytes_value(&[0; 32])),
+                ]),
+            ),
+        ];
+
+        let executed = executed_multisigs(&events, &deeper_metadata());
+        assert_eq!(executed.len(), 1);
+        assert_eq!(executed.get(&1), Some(&vec![multi]));
+    }
+
+    #[test]
+    fn test_multi_account_id_is_order_independent() {
+        let alice = account("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ");
+        let bob = account("5GNJqTPyNqANBkUVMN1LPPrxXnFouWXoe2wNSmmEoLctxiZY");
+
+        assert_eq!(
+            multi_account_id(&alice, &[bob.clone()], 2),
+            multi_account_id(&bob, &[alice.clone()], 2)
+        );
+        assert_ne!(
+            multi_account_id(&alice, &[bob.clone()], 2),
+            multi_account_id(&alice, &[bob], 1)
+        );
+    }
+}
@@ -1,85 +1,317 @@
-use codec::Encode;
 use desub_current::decoder;
 use desub_current::value::{Composite, Primitive, Value};
 use desub_current::Metadata;
-use sp_core::crypto::AccountId32;
+use scale_info::{PortableRegistry, TypeDef};
+use sp_core::crypto::{AccountId32, Ss58Codec};
 use sp_core::ByteArray;
 
+use crate::error::DecodeError;
+use crate::legacy_decoder::LegacyMetadata;
+use crate::storage_key::{StorageKey, StorageKeyDecoder};
+
 // static V14_METADATA_DEEPER_SCALE: &[u8] = include_bytes!("../data/v14_metadata_deeper.scale");
 
 // TODO: read metadata from database
-pub fn deeper_metadata() -> Metadata {
+pub fn deeper_metadata() -> BlockMetadata {
     let meta = include_bytes!("../data/v14_metadata_deeper.scale");
-    Metadata::from_bytes(meta).expect("valid metadata")
+    BlockMetadata::from_bytes(14, meta).expect("valid metadata")
 }
 
-pub fn decode_account_id(un: Vec<Value>) -> Result<AccountId32, Box<dyn std::error::Error>> {
-    let mut account_id: Vec<u8> = vec![];
-    for c in &un {
-        match c {
-            Value::Primitive(Primitive::U64(inner)) => {
-                account_id.push(*inner as u8);
-            }
-            Value::Primitive(Primitive::U32(inner)) => {
-                account_id.push(*inner as u8);
-            }
-            Value::Primitive(Primitive::U16(inner)) => {
-                account_id.push(*inner as u8);
-            }
-            Value::Primitive(Primitive::U8(inner)) => {
-                account_id.push(*inner as u8);
-            }
+/// Metadata of the runtime a block was produced with.
+pub enum BlockMetadata {
+    /// metadata v14 or newer, decoded by desub-current, with its storage keys indexed
+    Current(Metadata, StorageKeyDecoder),
+    /// metadata older than v14, decoded by desub-legacy
+    Legacy(LegacyMetadata),
+}
 
-            Value::Primitive(Primitive::I64(inner)) => {
-                account_id.push(*inner as u8);
-            }
-            Value::Primitive(Primitive::I32(inner)) => {
-                account_id.push(*inner as u8);
-            }
-            Value::Primitive(Primitive::I16(inner)) => {
-                account_id.push(*inner as u8);
-            }
-            Value::Primitive(Primitive::I8(inner)) => {
-                account_id.push(*inner as u8);
+impl BlockMetadata {
+    pub fn from_bytes(spec: u32, meta: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        if metadata_version(meta) >= Some(14) {
+            Ok(BlockMetadata::Current(
+                Metadata::from_bytes(meta)?,
+                StorageKeyDecoder::from_bytes(meta)?,
+            ))
+        } else {
+            Ok(BlockMetadata::Legacy(LegacyMetadata::from_bytes(
+                spec, meta,
+            )?))
+        }
+    }
+
+    /// Decodes a hex storage key into its pallet, entry and map keys. Legacy metadata has
+    /// no type registry to decode the map keys with.
+    pub fn decode_storage_key(&self, storage_key: &str) -> Result<StorageKey, DecodeError> {
+        match self {
+            BlockMetadata::Current(meta, keys) => keys.decode_key(meta, storage_key),
+            BlockMetadata::Legacy(_) => Err(DecodeError::Legacy(String::from(
+                "storage key decoding needs metadata v14",
+            ))),
+        }
+    }
+
+    /// Type registry of metadata v14, legacy metadata has none.
+    pub fn types(&self) -> Option<&PortableRegistry> {
+        match self {
+            BlockMetadata::Current(_, keys) => Some(keys.types()),
+            BlockMetadata::Legacy(_) => None,
+        }
+    }
+}
+
+/// Reads the version byte following the `meta` magic number of encoded metadata.
+pub fn metadata_version(meta: &[u8]) -> Option<u8> {
+    match meta {
+        [b'm', b'e', b't', b'a', version, ..] => Some(*version),
+        _ => None,
+    }
+}
+
+/// `twox_128(pallet) ++ twox_128(item)`, the prefix shared by every key of a storage item.
+pub fn storage_prefix(pallet: &str, item: &str) -> Vec<u8> {
+    let mut key = sp_core::twox_128(pallet.as_bytes()).to_vec();
+    key.extend(sp_core::twox_128(item.as_bytes()).iter());
+
+    key
+}
+
+/// Reads the account id of a map keyed by a `blake2_128_concat` hashed `AccountId32`,
+/// the key must be `prefix ++ blake2_128(account_id) ++ account_id`.
+pub fn blake2_128_concat_account_id(key: &[u8], prefix: &[u8]) -> Result<AccountId32, DecodeError> {
+    let unknown_key = || DecodeError::UnknownStorageKey(hex::encode(key));
+    let hashed = key.strip_prefix(prefix).ok_or_else(unknown_key)?;
+    if hashed.len() != 16 + 32 {
+        return Err(unknown_key());
+    }
+    let (hash, account_id) = hashed.split_at(16);
+    if sp_core::blake2_128(account_id) != hash {
+        return Err(DecodeError::InvalidAccountId);
+    }
+    AccountId32::from_slice(account_id).map_err(|_| DecodeError::InvalidAccountId)
+}
+
+/// Reads an account id from the shapes desub decodes an `AccountId32` into when its type is
+/// not known:
+/// - 32 bytes wrapped in unnamed composites (or in nested json arrays)
+/// - `MultiAddress::Id` and `MultiAddress::Address32`
+/// - a SS58 string, as found in legacy json
+///
+/// Every byte must be an integer in `0..=255`, byte arrays of any other length and
+/// `MultiAddress::Index`, `Raw` and `Address20` are rejected, see `typed_account_id` to read
+/// an account by its type.
+pub fn value_to_account_id(value: &Value) -> Result<AccountId32, DecodeError> {
+    match value {
+        Value::Variant(variant) => match (variant.name.as_str(), &variant.values) {
+            ("Id" | "Address32", Composite::Unnamed(values)) if values.len() == 1 => {
+                value_to_account_id(&values[0])
             }
+            ("Address20", _) => Err(DecodeError::EvmAddress),
+            _ => Err(DecodeError::InvalidAccountId),
+        },
+        Value::Composite(Composite::Unnamed(values)) if values.len() == 1 => {
+            value_to_account_id(&values[0])
+        }
+        Value::Composite(Composite::Unnamed(values)) => account_id_bytes(values),
+        Value::Primitive(Primitive::Str(addr)) => {
+            AccountId32::from_ss58check(addr).map_err(|_| DecodeError::InvalidAccountId)
+        }
+        _ => Err(DecodeError::InvalidAccountId),
+    }
+}
 
-            _ => {
-                continue;
+/// Reads an account id decoded as the type `ty` of `types`:
+/// - `AccountId32`
+/// - `MultiAddress::Id` and `MultiAddress::Address32`
+/// - a newtype of one of them
+///
+/// `H160` and `MultiAddress::Address20` are rejected with `EvmAddress`, deeper-chain has no
+/// mapping from evm addresses to substrate accounts.
+pub fn typed_account_id(
+    types: &PortableRegistry,
+    ty: u32,
+    value: &Value,
+) -> Result<AccountId32, DecodeError> {
+    let ty = types.resolve(ty).ok_or(DecodeError::InvalidAccountId)?;
+    match ty.path().segments().last().map(String::as_str) {
+        Some("AccountId32") => return value_to_account_id(value),
+        Some("H160") => return Err(DecodeError::EvmAddress),
+        _ => {}
+    }
+
+    match (ty.type_def(), value) {
+        (TypeDef::Variant(variants), Value::Variant(variant))
+            if ty.path().segments().last().map(String::as_str) == Some("MultiAddress") =>
+        {
+            let field = variants
+                .variants()
+                .iter()
+                .find(|def| def.name() == &variant.name)
+                .and_then(|def| def.fields().first());
+            match (variant.name.as_str(), &variant.values, field) {
+                ("Id", Composite::Unnamed(values), Some(field)) if values.len() == 1 => {
+                    typed_account_id(types, field.ty().id(), &values[0])
+                }
+                ("Address32", Composite::Unnamed(values), _) if values.len() == 1 => {
+                    value_to_account_id(&values[0])
+                }
+                ("Address20", _, _) => Err(DecodeError::EvmAddress),
+                _ => Err(DecodeError::InvalidAccountId),
             }
         }
+        (TypeDef::Composite(composite), Value::Composite(values))
+            if composite.fields().len() == 1 =>
+        {
+            let value = match values {
+                Composite::Named(values) if values.len() == 1 => &values[0].1,
+                Composite::Unnamed(values) if values.len() == 1 => &values[0],
+                _ => return Err(DecodeError::InvalidAccountId),
+            };
+            typed_account_id(types, composite.fields()[0].ty().id(), value)
+        }
+        _ => Err(DecodeError::InvalidAccountId),
+    }
+}
+
+/// Reads an account id field of the type `ty`. With a type registry (metadata v14) the field
+/// is read by its type and a field of unknown type is rejected, legacy values have no type
+/// and are read by their layout.
+pub fn field_account_id(
+    types: Option<&PortableRegistry>,
+    ty: Option<u32>,
+    value: &Value,
+) -> Result<AccountId32, DecodeError> {
+    match (types, ty) {
+        (Some(types), Some(ty)) => typed_account_id(types, ty, value),
+        (Some(_), None) => Err(DecodeError::TypeMismatch(format!(
+            "account id of unknown type, got {:?}",
+            value
+        ))),
+        (None, _) => value_to_account_id(value),
+    }
+}
+
+/// Type ids of the fields of the composite or tuple type `ty`.
+pub fn field_types(types: &PortableRegistry, ty: u32) -> Option<Vec<u32>> {
+    match types.resolve(ty)?.type_def() {
+        TypeDef::Composite(composite) => Some(
+            composite
+                .fields()
+                .iter()
+                .map(|field| field.ty().id())
+                .collect(),
+        ),
+        TypeDef::Tuple(tuple) => Some(tuple.fields().iter().map(|ty| ty.id()).collect()),
+        _ => None,
+    }
+}
+
+/// Type id of the field `name` of the composite type `ty`.
+pub fn named_field_type(types: &PortableRegistry, ty: u32, name: &str) -> Option<u32> {
+    match types.resolve(ty)?.type_def() {
+        TypeDef::Composite(composite) => composite
+            .fields()
+            .iter()
+            .find(|field| field.name().map(String::as_str) == Some(name))
+            .map(|field| field.ty().id()),
+        _ => None,
+    }
+}
+
+/// Type ids of the fields of the variant `name` of the enum type `ty`.
+pub fn variant_field_types(types: &PortableRegistry, ty: u32, name: &str) -> Option<Vec<u32>> {
+    match types.resolve(ty)?.type_def() {
+        TypeDef::Variant(variants) => variants
+            .variants()
+            .iter()
+            .find(|def| def.name().as_str() == name)
+            .map(|def| def.fields().iter().map(|field| field.ty().id()).collect()),
+        _ => None,
+    }
+}
+
+/// Type id of the items of the sequence or array type `ty`, newtypes such as `BoundedVec`
+/// are unwrapped.
+pub fn item_type(types: &PortableRegistry, ty: u32) -> Option<u32> {
+    match types.resolve(ty)?.type_def() {
+        TypeDef::Sequence(seq) => Some(seq.type_param().id()),
+        TypeDef::Array(array) => Some(array.type_param().id()),
+        TypeDef::Composite(composite) if composite.fields().len() == 1 => {
+            item_type(types, composite.fields()[0].ty().id())
+        }
+        _ => None,
     }
+}
 
-    // TODO: use map_err or other
-    match AccountId32::from_slice(&account_id) {
-        Ok(account) => Ok(account),
-        Err(()) => Err(Box::new(crate::DecodeAccountIdFailed)),
+fn account_id_bytes(values: &[Value]) -> Result<AccountId32, DecodeError> {
+    let bytes = value_bytes(values).ok_or(DecodeError::InvalidAccountId)?;
+    AccountId32::from_slice(&bytes).map_err(|_| DecodeError::InvalidAccountId)
+}
+
+/// Bytes of a byte array, `None` if an item is not an integer in `0..=255`.
+pub fn value_bytes(values: &[Value]) -> Option<Vec<u8>> {
+    values
+        .iter()
+        .map(|value| match value {
+            Value::Primitive(Primitive::U8(byte)) => Some(*byte),
+            Value::Primitive(Primitive::U16(byte)) => u8::try_from(*byte).ok(),
+            Value::Primitive(Primitive::U32(byte)) => u8::try_from(*byte).ok(),
+            Value::Primitive(Primitive::U64(byte)) => u8::try_from(*byte).ok(),
+            Value::Primitive(Primitive::U128(byte)) => u8::try_from(*byte).ok(),
+            Value::Primitive(Primitive::I8(byte)) => u8::try_from(*byte).ok(),
+            Value::Primitive(Primitive::I16(byte)) => u8::try_from(*byte).ok(),
+            Value::Primitive(Primitive::I32(byte)) => u8::try_from(*byte).ok(),
+            Value::Primitive(Primitive::I64(byte)) => u8::try_from(*byte).ok(),
+            Value::Primitive(Primitive::I128(byte)) => u8::try_from(*byte).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Reads the account id of a `pallet.item` map keyed by a `blake2_128_concat` hashed
+/// `AccountId32`. Legacy metadata has no type registry, its keys are read by layout.
+pub fn map_key_account_id(
+    storage_key: &str,
+    pallet: &str,
+    item: &str,
+    meta: &BlockMetadata,
+) -> Result<AccountId32, DecodeError> {
+    match meta {
+        BlockMetadata::Current(_, keys) => meta
+            .decode_storage_key(storage_key)?
+            .account_id(keys.types(), 0),
+        BlockMetadata::Legacy(_) => {
+            blake2_128_concat_account_id(&hex::decode(storage_key)?, &storage_prefix(pallet, item))
+        }
     }
 }
 
+// keys of a known account, the decoders scan the storage by prefix and only tests build them
+#[cfg(test)]
 pub fn system_account_key(account_id: AccountId32) -> Vec<u8> {
-    let mut key = sp_core::twox_128("System".as_bytes()).to_vec();
-    key.extend(sp_core::twox_128("Account".as_bytes()).iter());
-    let addr_encode = account_id.encode();
+    let mut key = storage_prefix("System", "Account");
+    let addr_encode = codec::Encode::encode(&account_id);
     key.extend(sp_core::blake2_128(&addr_encode));
     key.extend(&addr_encode); // blake2_128_concat
 
     key
 }
 
+#[cfg(test)]
 pub fn staking_delegators_key(account_id: AccountId32) -> Vec<u8> {
     let mut key = sp_core::twox_128("Staking".as_bytes()).to_vec();
     key.extend(sp_core::twox_128("Delegators".as_bytes()).iter());
-    let addr_encode = account_id.encode();
+    let addr_encode = codec::Encode::encode(&account_id);
     key.extend(sp_core::blake2_128(&addr_encode));
     key.extend(&addr_encode); // blake2_128_concat
 
     key
 }
 
+#[cfg(test)]
 pub fn user_credit_key(account_id: AccountId32) -> Vec<u8> {
     let mut key = sp_core::twox_128("Credit".as_bytes()).to_vec();
     key.extend(sp_core::twox_128("UserCredit".as_bytes()).iter());
-    let addr_encode = account_id.encode();
+    let addr_encode = codec::Encode::encode(&account_id);
     key.extend(sp_core::blake2_128(&addr_encode));
     key.extend(&addr_encode); // blake2_128_concat
 
@@ -93,25 +325,35 @@ pub fn event_key() -> Vec<u8> {
     key
 }
 
-pub fn decode_storage(storage_key: &str, storage_val: &str, meta: &Metadata) -> Value {
-    let storage = decoder::decode_storage(&meta);
-    let key_bytes = hex::decode(storage_key).unwrap();
+pub fn decode_storage(
+    storage_key: &str,
+    storage_val: &str,
+    meta: &BlockMetadata,
+) -> Result<Value, DecodeError> {
+    let meta = match meta {
+        BlockMetadata::Current(meta, _) => meta,
+        BlockMetadata::Legacy(meta) => {
+            return crate::legacy_decoder::decode_storage(storage_key, storage_val, meta)
+        }
+    };
+    let storage = decoder::decode_storage(meta);
+    let key_bytes = hex::decode(storage_key)?;
     let entry = storage
         .decode_key(meta, &mut key_bytes.as_slice())
-        .expect("can decode storage");
-    decoder::decode_value_by_id(
-        meta,
-        &entry.ty,
-        &mut hex::decode(&storage_val).unwrap().as_slice(),
-    )
-    .unwrap()
+        .map_err(|_| DecodeError::UnknownStorageKey(storage_key.to_string()))?;
+    decoder::decode_value_by_id(meta, &entry.ty, &mut hex::decode(storage_val)?.as_slice())
+        .map_err(|err| DecodeError::TypeMismatch(format!("{:?}", err)))
 }
 
 #[cfg(test)]
 mod tests {
-    use sp_core::crypto::Ss58Codec;
+    use desub_current::value::Variant;
+    use scale_info::{meta_type, Registry};
+    use sp_core::H160;
+    use sp_runtime::MultiAddress;
 
     use super::*;
+    use crate::test_util::{account_value, bytes_value};
 
     #[test]
     fn test_system_account_key() {
@@ -123,6 +365,202 @@ mod tests {
         assert_eq!(hex::encode(key), "26aa394eea5630e07c48ae0c9558cef7b99d880ec681799c0cf30e8886371da93594ef778a4003043f6d977057644d65a88b59afe73f0e769e4f9d85cd40fd13f0874446f22d2ab6780f9cb89059307e");
     }
 
+    #[test]
+    fn test_blake2_128_concat_account_id() {
+        let test_addr =
+            AccountId32::from_ss58check("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
+                .unwrap();
+        let prefix = storage_prefix("System", "Account");
+        let mut key = system_account_key(test_addr.clone());
+
+        assert_eq!(blake2_128_concat_account_id(&key, &prefix), Ok(test_addr));
+        assert!(matches!(
+            blake2_128_concat_account_id(&key, &storage_prefix("Credit", "UserCredit")),
+            Err(DecodeError::UnknownStorageKey(_))
+        ));
+        key[prefix.len()] ^= 1;
+        assert_eq!(
+            blake2_128_concat_account_id(&key, &prefix),
+            Err(DecodeError::InvalidAccountId)
+        );
+    }
+
+    #[test]
+    fn test_value_to_account_id() {
+        let addr = AccountId32::from_ss58check("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
+            .unwrap();
+        let account = account_value(&addr);
+
+        assert_eq!(value_to_account_id(&account), Ok(addr.clone()));
+        let multi_address = Value::Variant(Variant {
+            name: String::from("Id"),
+            values: Composite::Unnamed(vec![account]),
+        });
+        assert_eq!(value_to_account_id(&multi_address), Ok(addr.clone()));
+        let ss58 = Value::Primitive(Primitive::Str(addr.to_ss58check()));
+        assert_eq!(value_to_account_id(&ss58), Ok(addr));
+
+        // without its type a 20 bytes array is not guessed to be an evm address
+        assert_eq!(
+            value_to_account_id(&bytes_value(&[0x11; 20])),
+            Err(DecodeError::InvalidAccountId)
+        );
+        let address20 = Value::Variant(Variant {
+            name: String::from("Address20"),
+            values: Composite::Unnamed(vec![bytes_value(&[0x11; 20])]),
+        });
+        assert_eq!(
+            value_to_account_id(&address20),
+            Err(DecodeError::EvmAddress)
+        );
+        let index = Value::Variant(Variant {
+            name: String::from("Index"),
+            values: Composite::Unnamed(vec![Value::Primitive(Primitive::U32(1))]),
+        });
+        assert_eq!(
+            value_to_account_id(&index),
+            Err(DecodeError::InvalidAccountId)
+        );
+    }
+
+    #[test]
+    fn test_typed_account_id() {
+        let mut registry = Registry::new();
+        let account_ty = registry.register_type(&meta_type::<AccountId32>()).id();
+        let multi_address_ty = registry
+            .register_type(&meta_type::<MultiAddress<AccountId32, u32>>())
+            .id();
+        let h160_ty = registry.register_type(&meta_type::<H160>()).id();
+        let types = PortableRegistry::from(registry);
+        let addr = AccountId32::from_ss58check("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
+            .unwrap();
+        let bytes: &[u8] = addr.as_ref();
+        let account = account_value(&addr);
+        let multi_address = |name: &str, value: Value| {
+            Value::Variant(Variant {
+                name: String::from(name),
+                values: Composite::Unnamed(vec![value]),
+            })
+        };
+
+        assert_eq!(
+            typed_account_id(&types, account_ty, &account),
+            Ok(addr.clone())
+        );
+        assert_eq!(
+            typed_account_id(&types, multi_address_ty, &multi_address("Id", account)),
+            Ok(addr.clone())
+        );
+        assert_eq!(
+            typed_account_id(
+                &types,
+                multi_address_ty,
+                &multi_address("Address32", bytes_value(bytes))
+            ),
+            Ok(addr)
+        );
+
+        let h160 = Value::Composite(Composite::Unnamed(vec![bytes_value(&[0x11; 20])]));
+        assert_eq!(
+            typed_account_id(&types, h160_ty, &h160),
+            Err(DecodeError::EvmAddress)
+        );
+        assert_eq!(
+            typed_account_id(
+                &types,
+                multi_address_ty,
+                &multi_address("Address20", bytes_value(&[0x11; 20]))
+            ),
+            Err(DecodeError::EvmAddress)
+        );
+        assert_eq!(
+            typed_account_id(
+                &types,
+                multi_address_ty,
+                &multi_address("Index", Value::Primitive(Primitive::U32(1)))
+            ),
+            Err(DecodeError::InvalidAccountId)
+        );
+    }
+
+    #[test]
+    fn test_registry_field_types() {
+        let meta = deeper_metadata();
+        let (types, keys) = match &meta {
+            BlockMetadata::Current(_, keys) => (keys.types(), keys),
+            BlockMetadata::Legacy(_) => unreachable!(),
+        };
+        let path = |ty: u32| types.resolve(ty).unwrap().path().segments().join("::");
+
+        // `DelegatorData { delegator, delegated_validators: Vec<AccountId>, .. }`
+        let delegator_data = keys.value_type("Staking", "Delegators").unwrap();
+        assert_eq!(
+            field_types(types, delegator_data).map(|tys| tys.len()),
+            Some(4)
+        );
+        let validators = named_field_type(types, delegator_data, "delegated_validators").unwrap();
+        assert_eq!(
+            item_type(types, validators).map(path),
+            Some(String::from("sp_core::crypto::AccountId32"))
+        );
+        assert_eq!(named_field_type(types, delegator_data, "unknown"), None);
+        // `Option<EraIndex>`
+        let unrewarded_since = named_field_type(types, delegator_data, "unrewarded_since").unwrap();
+        assert_eq!(
+            variant_field_types(types, unrewarded_since, "None"),
+            Some(vec![])
+        );
+        assert_eq!(
+            variant_field_types(types, unrewarded_since, "Some").map(|tys| tys.len()),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_field_account_id() {
+        let meta = deeper_metadata();
+        let addr = AccountId32::from_ss58check("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
+            .unwrap();
+        let account = account_value(&addr);
+
+        // legacy values are read by their layout
+        assert_eq!(field_account_id(None, None, &account), Ok(addr));
+        assert!(matches!(
+            field_account_id(meta.types(), None, &account),
+            Err(DecodeError::TypeMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_value_bytes_signed() {
+        let values = vec![
+            Value::Primitive(Primitive::I8(1)),
+            Value::Primitive(Primitive::I16(2)),
+            Value::Primitive(Primitive::I32(3)),
+            Value::Primitive(Primitive::I64(4)),
+            Value::Primitive(Primitive::I128(255)),
+        ];
+        assert_eq!(value_bytes(&values), Some(vec![1, 2, 3, 4, 255]));
+        assert_eq!(value_bytes(&[Value::Primitive(Primitive::I32(-1))]), None);
+    }
+
+    #[test]
+    fn test_value_to_account_id_rejects_bad_bytes() {
+        let mut values = vec![Value::Primitive(Primitive::U64(1)); 32];
+        assert!(value_to_account_id(&Value::Composite(Composite::Unnamed(values.clone()))).is_ok());
+
+        // used to be truncated to 0x00 by `as u8`
+        values[0] = Value::Primitive(Primitive::U64(256));
+        assert_eq!(
+            value_to_account_id(&Value::Composite(Composite::Unnamed(values))),
+            Err(DecodeError::InvalidAccountId)
+        );
+        assert_eq!(
+            value_to_account_id(&bytes_value(&[1; 31])),
+            Err(DecodeError::InvalidAccountId)
+        );
+    }
+
     #[test]
     fn test_user_credit_key() {
         let test_addr =
@@ -139,7 +577,7 @@ mod tests {
         let storage_val = "01006400000000000000010000000000010e010000";
         let meta = crate::common::deeper_metadata();
 
-        let val = decode_storage(storage_key, storage_val, &meta);
+        let val = decode_storage(storage_key, storage_val, &meta).unwrap();
         match val {
             Value::Composite(Composite::Named(data)) => {
                 let credit_val = data[1].1.clone();
@@ -150,6 +588,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_decode_storage_errors() {
+        let meta = crate::common::deeper_metadata();
+
+        assert!(matches!(
+            decode_storage("zz", "00", &meta),
+            Err(DecodeError::BadHex(_))
+        ));
+        assert!(matches!(
+            decode_storage("00112233", "00", &meta),
+            Err(DecodeError::UnknownStorageKey(_))
+        ));
+        // credit key with a truncated value
+        let storage_key = "83e0731810368fb22559f084ed61d427f7eb0b356c4455f32f2dab8a7aa408d83594ef778a4003043f6d977057644d65a88b59afe73f0e769e4f9d85cd40fd13f0874446f22d2ab6780f9cb89059307e";
+        assert!(matches!(
+            decode_storage(storage_key, "0100", &meta),
+            Err(DecodeError::TypeMismatch(_))
+        ));
+    }
+
     #[test]
     fn test_staking_delegation_key() {
         let test_addr =
@@ -160,6 +618,15 @@ mod tests {
         assert_eq!(hex::encode(key), "5f3e4907f716ac89b6347d15ececedcae1c5df6d2773f08c7b6b1b6d0139c22ad8d95300d3869c73662575801e97110240cf86fde7072801dc43df9199deb195ebeec77b6831527344bff70b799ab555");
     }
 
+    #[test]
+    fn test_metadata_version() {
+        let meta = include_bytes!("../data/v14_metadata_deeper.scale");
+
+        assert_eq!(metadata_version(meta), Some(14));
+        assert_eq!(metadata_version(&[0x6d, 0x65, 0x74, 0x61, 12, 0]), Some(12));
+        assert_eq!(metadata_version(&[1, 2, 3]), None);
+    }
+
     #[test]
     fn test_event_key() {
         let key = event_key();
@@ -1,314 +1,368 @@
-use desub_current::value::{self, Composite, Value};
-use sp_core::crypto::AccountId32;
-use std::collections::HashSet;
+use async_trait::async_trait;
+use desub_current::value::Value;
+use sp_core::crypto::{AccountId32, Ss58Codec};
+use sqlx::postgres::Postgres;
+use sqlx::types::Decimal;
+use sqlx::Transaction;
+use std::collections::HashMap;
 
-pub fn get_credit_changed_account_ids(ext: &str) -> HashSet<AccountId32> {
-    let mut account_ids: HashSet<AccountId32> = HashSet::new();
+use crate::common::BlockMetadata;
+use crate::error::{DecodeError, DecodeFailure};
+use crate::pallet_decoder::{Block, PalletDecoder};
+use crate::value_ext::ValueExt;
 
-    match serde_json::from_str::<Vec<crate::CurrentExtrinsic>>(ext) {
-        Ok(extrinsics) => {
-            for extrinsic in &extrinsics {
-                // TODO: check sudo
-                if extrinsic.current.call_data.pallet_name == "Sudo" {
-                    if extrinsic.current.call_data.arguments.len() >= 1 {
-                        match extrinsic.current.call_data.arguments[0].clone() {
-                            Value::Composite(Composite::Named(cn)) => {
-                                match cn[1].1.clone() {
-                                    Value::Composite(Composite::Unnamed(cn1)) => {
-                                        match cn1[0].clone() {
-                                            Value::Composite(Composite::Named(cn2)) => {
-                                                if cn2[0].0 == "name"
-                                                    && cn2[0].1
-                                                        == Value::Primitive(value::Primitive::Str(
-                                                            String::from(
-                                                                "add_or_update_credit_data",
-                                                            ),
-                                                        ))
-                                                {
-                                                    match cn2[1].1.clone() {
-                                                        Value::Composite(Composite::Named(cn3)) => {
-                                                            if cn3[0].0 == "account_id" {
-                                                                match cn3[0].1.clone() {
-                                                                    Value::Composite(
-                                                                        Composite::Unnamed(cn4),
-                                                                    ) => match cn4[0].clone() {
-                                                                        Value::Composite(
-                                                                            Composite::Unnamed(cn5),
-                                                                        ) => {
-                                                                            match crate::common::decode_account_id(cn5) {
-                                                                    Ok(account) => {
-                                                                      account_ids.insert(account);
-                                                                    },
-                                                                    _ => {}, // ignore error
-                                                                  }
-                                                                        }
-                                                                        _ => {}
-                                                                    },
-                                                                    _ => {}
-                                                                };
-                                                            }
-                                                        }
-                                                        _ => {}
-                                                    };
-                                                }
-                                            }
-                                            _ => {}
-                                        };
-                                    }
-                                    _ => {}
-                                };
-                            }
-                            _ => {}
-                        };
-                    }
-                }
-                // for argument in extrinsic.current.call_data.arguments.clone() {
+/// Hex prefix of the `Credit.UserCredit` storage keys.
+pub fn user_credit_prefix() -> String {
+    hex::encode(crate::common::storage_prefix("Credit", "UserCredit"))
+}
 
-                // }
+/// Credits reported by the `Credit` pallet events of a block, in event order. A credit event
+/// which can't be decoded is an error, the other events are skipped.
+pub fn get_event_credits<'a>(
+    events: impl IntoIterator<Item = &'a Value>,
+    meta: &BlockMetadata,
+) -> Vec<Result<(AccountId32, CreditData), DecodeError>> {
+    let mut credits = vec![];
+    for record in events {
+        let (name, fields) = match crate::event_decoder::event_variant(record) {
+            Some(("Credit", name, fields)) => (name, fields),
+            _ => continue,
+        };
+        let account_id = |account: &Value| {
+            crate::event_decoder::event_account_id(meta, "Credit", name, 0, account)
+        };
+        let credit = match (name, fields.as_slice()) {
+            ("CreditUpdateSuccess", [account, credit]) => account_id(account)
+                .and_then(|account| Ok((account, CreditData::with_credit(credit.as_u64()?)))),
+            ("CreditDataAdded" | "CreditDataUpdated", [account, data]) => {
+                account_id(account).and_then(|account| Ok((account, credit_data_of(data)?)))
             }
-            account_ids
+            ("CreditUpdateSuccess" | "CreditDataAdded" | "CreditDataUpdated", _) => Err(
+                DecodeError::TypeMismatch(format!("{} with {} fields", name, fields.len())),
+            ),
+            _ => continue,
+        };
+        credits.push(credit);
+    }
+    credits
+}
+
+/// Where the credit of a `block_credit` row was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CreditSource {
+    /// the whole `Credit.UserCredit` entry
+    Storage,
+    /// the `Credit.UserCredit` entry was removed, the row has no credit data
+    Removed,
+    /// a `Credit` event of an account whose storage change is missing, `CreditUpdateSuccess`
+    /// only reports the credit
+    Event,
+}
+
+impl CreditSource {
+    /// value of the `source` column of `block_credit`
+    pub fn name(&self) -> &'static str {
+        match self {
+            CreditSource::Storage => "storage",
+            CreditSource::Removed => "removed",
+            CreditSource::Event => "event",
         }
-        Err(_) => account_ids,
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use sp_core::crypto::Ss58Codec;
+/// `CreditData` of an account, the fields missing from the runtime version of the block
+/// (or from the event the credit was read from) are `None`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CreditData {
+    pub campaign_id: Option<u16>,
+    pub credit: u64,
+    pub initial_credit_level: Option<u8>,
+    pub rank_in_initial_credit_level: Option<u32>,
+    pub number_of_referees: Option<u8>,
+    pub current_credit_level: Option<u8>,
+    pub reward_eras: Option<u32>,
+    pub expiration: Option<u32>,
+}
 
-    use super::*;
+impl CreditData {
+    /// Credit data known only by its credit value, e.g. from `CreditUpdateSuccess`.
+    pub fn with_credit(credit: u64) -> Self {
+        Self {
+            credit,
+            ..Default::default()
+        }
+    }
+}
 
-    #[test]
-    fn test_sudo_add_credit() {
-        let s = r##"[
-  {
-    "Current": {
-      "call_data": {
-        "ty": {
-          "docs": [
-            "Set the current time.",
-            "",
-            "This call should be invoked exactly once per block. It will panic at the finalization",
-            "phase, if this call hasn't been invoked by that time.",
-            "",
-            "The timestamp should be greater than the previous one by the amount specified by",
-            "`MinimumPeriod`.",
-            "",
-            "The dispatch origin for this call must be `Inherent`.",
-            "",
-            "# <weight>",
-            "- `O(1)` (Note that implementations of `OnTimestampSet` must also be `O(1)`)",
-            "- 1 storage read and 1 storage mutation (codec `O(1)`). (because of `DidUpdate::take` in",
-            "  `on_finalize`)",
-            "- 1 event handler `on_timestamp_set`. Must be `O(1)`.",
-            "# </weight>"
-          ],
-          "name": "set",
-          "index": 0,
-          "fields": [
-            {
-              "name": "now",
-              "type": 152,
-              "typeName": "T::Moment"
-            }
-          ]
-        },
-        "arguments": [
-          1649323090002
-        ],
-        "pallet_name": "Timestamp"
-      },
-      "signature": null
+/// Decodes a `Credit.UserCredit` storage value.
+pub fn get_credit(
+    storage_key: &str,
+    storage_val: &str,
+    meta: &BlockMetadata,
+) -> Result<CreditData, DecodeError> {
+    credit_data_of(&crate::common::decode_storage(
+        storage_key,
+        storage_val,
+        meta,
+    )?)
+}
+
+fn credit_data_of(data: &Value) -> Result<CreditData, DecodeError> {
+    let level = |name: &str| {
+        data.opt_field(name)
+            .map(|level| credit_level(level.variant_name()?))
+            .transpose()
+    };
+
+    Ok(CreditData {
+        campaign_id: data
+            .opt_field("campaign_id")
+            .map(|v| v.as_u16())
+            .transpose()?,
+        credit: data.field("credit")?.as_u64()?,
+        initial_credit_level: level("initial_credit_level")?,
+        rank_in_initial_credit_level: data
+            .opt_field("rank_in_initial_credit_level")
+            .map(|v| v.as_u32())
+            .transpose()?,
+        number_of_referees: data
+            .opt_field("number_of_referees")
+            .map(|v| v.as_u8())
+            .transpose()?,
+        current_credit_level: level("current_credit_level")?,
+        reward_eras: data
+            .opt_field("reward_eras")
+            .map(|v| v.as_u32())
+            .transpose()?,
+        expiration: data
+            .opt_field("expiration")
+            .map(|v| v.as_u32())
+            .transpose()?,
+    })
+}
+
+// `CreditLevel` variants are named after their level
+fn credit_level(name: &str) -> Result<u8, DecodeError> {
+    const LEVELS: [&str; 9] = [
+        "Zero", "One", "Two", "Three", "Four", "Five", "Six", "Seven", "Eight",
+    ];
+    LEVELS
+        .iter()
+        .position(|level| *level == name)
+        .map(|level| level as u8)
+        .ok_or_else(|| DecodeError::TypeMismatch(format!("unknown CreditLevel {}", name)))
+}
+
+/// Writes the credit data of the accounts into `block_credit`.
+pub struct CreditDecoder;
+
+#[async_trait]
+impl PalletDecoder for CreditDecoder {
+    /// (block_num, address, source, credit data)
+    type Row = (i32, String, CreditSource, CreditData);
+
+    fn name(&self) -> &'static str {
+        "credit"
     }
-  },
-  {
-    "Current": {
-      "call_data": {
-        "ty": {
-          "name": "note_min_gas_price_target",
-          "index": 0,
-          "fields": [
-            {
-              "name": "target",
-              "type": 106,
-              "typeName": "U256"
-            }
-          ]
-        },
-        "arguments": [
-          [
-            [
-              1,
-              0,
-              0,
-              0
-            ]
-          ]
-        ],
-        "pallet_name": "DynamicFee"
-      },
-      "signature": null
+
+    // every credit change (sudo, daily accumulation, traffic, slashing...) writes the
+    // `Credit.UserCredit` entry of the account
+    fn storage_prefixes(&self) -> Vec<String> {
+        vec![user_credit_prefix()]
+    }
+
+    // the `Credit` events cover the accounts whose storage change is missing
+    fn events(&self) -> Vec<(&'static str, &'static str)> {
+        vec![("Credit", "*")]
     }
-  },
-  {
-    "Current": {
-      "call_data": {
-        "ty": {
-          "docs": [
-            "Authenticates the sudo key and dispatches a function call with `Root` origin.",
-            "This function does not check the weight of the call, and instead allows the",
-            "Sudo user to specify the weight of the call.",
-            "",
-            "The dispatch origin for this call must be _Signed_.",
-            "",
-            "# <weight>",
-            "- O(1).",
-            "- The weight of this call is defined by the caller.",
-            "# </weight>"
-          ],
-          "name": "sudo_unchecked_weight",
-          "index": 1,
-          "fields": [
-            {
-              "name": "call",
-              "type": 137,
-              "typeName": "Box<<T as Config>::Call>"
-            },
-            {
-              "name": "weight",
-              "type": 8,
-              "typeName": "Weight"
+
+    fn decode(&self, block: &Block, rows: &mut Vec<Self::Row>, failures: &mut Vec<DecodeFailure>) {
+        let block_num = block.block_num();
+        let mut credits: HashMap<AccountId32, (CreditSource, CreditData)> = HashMap::new();
+        for (storage_key, storage_str) in block.storage() {
+            let addr = match crate::common::map_key_account_id(
+                storage_key,
+                "Credit",
+                "UserCredit",
+                block.meta(),
+            ) {
+                Ok(addr) => addr,
+                Err(err) => {
+                    failures.push(DecodeFailure::storage(
+                        block_num,
+                        storage_key,
+                        storage_str,
+                        err,
+                    ));
+                    continue;
+                }
+            };
+            if storage_str.is_empty() {
+                // the credit data was removed
+                credits.insert(addr, (CreditSource::Removed, CreditData::default()));
+                continue;
             }
-          ]
-        },
-        "arguments": [
-          {
-            "name": "Credit",
-            "values": [
-              {
-                "name": "add_or_update_credit_data",
-                "values": {
-                  "account_id": [
-                    [
-                      168,
-                      139,
-                      89,
-                      175,
-                      231,
-                      63,
-                      14,
-                      118,
-                      158,
-                      79,
-                      157,
-                      133,
-                      205,
-                      64,
-                      253,
-                      19,
-                      240,
-                      135,
-                      68,
-                      70,
-                      242,
-                      45,
-                      42,
-                      182,
-                      120,
-                      15,
-                      156,
-                      184,
-                      144,
-                      89,
-                      48,
-                      126
-                    ]
-                  ],
-                  "credit_data": {
-                    "credit": 100,
-                    "campaign_id": 1,
-                    "reward_eras": 270,
-                    "number_of_referees": 0,
-                    "current_credit_level": {
-                      "name": "One",
-                      "values": []
-                    },
-                    "initial_credit_level": {
-                      "name": "One",
-                      "values": []
-                    },
-                    "rank_in_initial_credit_level": 0
-                  }
+            match get_credit(storage_key, storage_str, block.meta()) {
+                Ok(credit) => {
+                    credits.insert(addr, (CreditSource::Storage, credit));
+                }
+                Err(err) => {
+                    failures.push(DecodeFailure::storage(
+                        block_num,
+                        storage_key,
+                        storage_str,
+                        err,
+                    ));
+                }
+            }
+        }
+        for credit in get_event_credits(block.events().map(|(_, record)| record), block.meta()) {
+            match credit {
+                Ok((addr, credit)) => {
+                    credits.entry(addr).or_insert((CreditSource::Event, credit));
                 }
-              }
-            ]
-          },
-          10000
-        ],
-        "pallet_name": "Sudo"
-      },
-      "signature": {
-        "address": {
-          "Id": "5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY"
-        },
-        "signature": {
-          "Sr25519": "50e13f3a4e56b6f9b4bb45a07540219ca739850ac20bd6c1310bc71fb5d35d6c60b8ff77d55b9352124562669aa3d8b3f7cffd309f6709a1aab24f8fa3e9bb80"
-        },
-        "extensions": [
-          [
-            "CheckNonZeroSender",
-            []
-          ],
-          [
-            "CheckSpecVersion",
-            []
-          ],
-          [
-            "CheckTxVersion",
-            []
-          ],
-          [
-            "CheckGenesis",
-            []
-          ],
-          [
-            "CheckMortality",
-            [
-              {
-                "name": "Mortal70",
-                "values": [
-                  1
-                ]
-              }
-            ]
-          ],
-          [
-            "CheckNonce",
-            [
-              0
-            ]
-          ],
-          [
-            "CheckWeight",
-            []
-          ],
-          [
-            "ChargeTransactionPayment",
-            [
-              0
-            ]
-          ]
-        ]
-      }
+                Err(err) => failures.push(block.event_failure(err)),
+            }
+        }
+        for (addr, (source, credit)) in credits {
+            rows.push((block_num, addr.to_ss58check(), source, credit));
+        }
     }
-  }
-]"##;
-        let account_ids = get_credit_changed_account_ids(s);
-        let exp = sp_core::crypto::AccountId32::from_ss58check(
-            "5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ",
+
+    async fn write(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        rows: Vec<Self::Row>,
+    ) -> Result<(), sqlx::Error> {
+        let mut to_insert_block_nums = vec![];
+        let mut to_insert_addrs = vec![];
+        let mut to_insert_sources = vec![];
+        let mut to_insert_campaign_ids = vec![];
+        let mut to_insert_credits = vec![];
+        let mut to_insert_initial_levels = vec![];
+        let mut to_insert_ranks = vec![];
+        let mut to_insert_referees = vec![];
+        let mut to_insert_current_levels = vec![];
+        let mut to_insert_reward_eras = vec![];
+        let mut to_insert_expirations = vec![];
+        rows.into_iter()
+            .for_each(|(block_num, addr, source, data)| {
+                to_insert_block_nums.push(block_num);
+                to_insert_addrs.push(addr);
+                to_insert_sources.push(source.name());
+                to_insert_campaign_ids.push(data.campaign_id.map(i32::from));
+                to_insert_credits.push(Decimal::from(data.credit));
+                to_insert_initial_levels.push(data.initial_credit_level.map(i16::from));
+                to_insert_ranks.push(data.rank_in_initial_credit_level.map(i64::from));
+                to_insert_referees.push(data.number_of_referees.map(i16::from));
+                to_insert_current_levels.push(data.current_credit_level.map(i16::from));
+                to_insert_reward_eras.push(data.reward_eras.map(i64::from));
+                to_insert_expirations.push(data.expiration.map(i64::from));
+            });
+        sqlx::query(
+            r#"INSERT INTO block_credit (block_num, address, source, campaign_id, credit, initial_credit_level,
+                rank_in_initial_credit_level, number_of_referees, current_credit_level, reward_eras, expiration)
+            SELECT * FROM UNNEST($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            ON CONFLICT (block_num, address) DO UPDATE SET source = EXCLUDED.source,
+                campaign_id = EXCLUDED.campaign_id,
+                credit = EXCLUDED.credit, initial_credit_level = EXCLUDED.initial_credit_level,
+                rank_in_initial_credit_level = EXCLUDED.rank_in_initial_credit_level,
+                number_of_referees = EXCLUDED.number_of_referees,
+                current_credit_level = EXCLUDED.current_credit_level,
+                reward_eras = EXCLUDED.reward_eras, expiration = EXCLUDED.expiration;"#,
         )
-        .unwrap();
+        .bind(&to_insert_block_nums)
+        .bind(&to_insert_addrs)
+        .bind(&to_insert_sources)
+        .bind(&to_insert_campaign_ids)
+        .bind(&to_insert_credits)
+        .bind(&to_insert_initial_levels)
+        .bind(&to_insert_ranks)
+        .bind(&to_insert_referees)
+        .bind(&to_insert_current_levels)
+        .bind(&to_insert_reward_eras)
+        .bind(&to_insert_expirations)
+        .execute(&mut *tx)
+        .await?;
+
+        Ok(())
+    }
+}
 
-        assert!(account_ids.contains(&exp));
+#[cfg(test)]
+mod tests {
+    use desub_current::value::{Composite, Primitive};
+
+    use super::*;
+    use crate::test_util::{account_value, event_record};
+
+    #[test]
+    fn test_get_credit() {
+        let storage_key = "83e0731810368fb22559f084ed61d427f7eb0b356c4455f32f2dab8a7aa408d83594ef778a4003043f6d977057644d65a88b59afe73f0e769e4f9d85cd40fd13f0874446f22d2ab6780f9cb89059307e";
+        let storage_val = "01006400000000000000010000000000010e010000";
+        let meta = crate::common::deeper_metadata();
+
+        assert_eq!(
+            get_credit(storage_key, storage_val, &meta),
+            Ok(CreditData {
+                campaign_id: Some(1),
+                credit: 100,
+                initial_credit_level: Some(1),
+                rank_in_initial_credit_level: Some(0),
+                number_of_referees: Some(0),
+                current_credit_level: Some(1),
+                reward_eras: Some(270),
+                expiration: None,
+            })
+        );
+        assert!(get_credit(storage_key, "0100", &meta).is_err());
+    }
+
+    #[test]
+    fn test_get_event_credits() {
+        let addr = AccountId32::from_ss58check("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
+            .unwrap();
+        let account = account_value(&addr);
+        let events = vec![
+            event_record(
+                0,
+                "Credit",
+                "CreditUpdateSuccess",
+                Composite::Unnamed(vec![account.clone(), Value::Primitive(Primitive::U64(120))]),
+            ),
+            event_record(
+                0,
+                "Credit",
+                "CreditUpdateFailed",
+                Composite::Unnamed(vec![account.clone(), Value::Primitive(Primitive::U64(130))]),
+            ),
+            event_record(
+                0,
+                "Balances",
+                "Transfer",
+                Composite::Unnamed(vec![account.clone(), Value::Primitive(Primitive::U64(1))]),
+            ),
+            // a credit event which can't be decoded is reported
+            event_record(
+                1,
+                "Credit",
+                "CreditUpdateSuccess",
+                Composite::Unnamed(vec![account, Value::Primitive(Primitive::Bool(true))]),
+            ),
+            event_record(1, "Credit", "CreditDataAdded", Composite::Unnamed(vec![])),
+        ];
+
+        let credits = get_event_credits(&events, &crate::common::deeper_metadata());
+        assert_eq!(credits.len(), 3);
+        assert_eq!(credits[0], Ok((addr, CreditData::with_credit(120))));
+        assert!(matches!(credits[1], Err(DecodeError::TypeMismatch(_))));
+        assert!(matches!(credits[2], Err(DecodeError::TypeMismatch(_))));
+    }
+
+    #[test]
+    fn test_credit_source_name() {
+        assert_eq!(CreditSource::Storage.name(), "storage");
+        assert_eq!(CreditSource::Removed.name(), "removed");
+        assert_eq!(CreditSource::Event.name(), "event");
     }
 }
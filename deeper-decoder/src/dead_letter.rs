@@ -0,0 +1,67 @@
+use sqlx::postgres::Postgres;
+use sqlx::{Executor, Transaction};
+
+use crate::cli_opts::DecoderKind;
+use crate::error::DecodeFailure;
+
+/// Blocks with at least one failure recorded by `decoder` between `from` and `to` (inclusive).
+pub async fn get_failed_blocks<'e, E>(
+    executor: E,
+    decoder: DecoderKind,
+    from: Option<i32>,
+    to: Option<i32>,
+) -> Result<Vec<i32>, sqlx::Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    sqlx::query_scalar(
+        "select distinct block_num from decode_failures where decoder = $1 and block_num between $2 and $3 order by block_num asc;",
+    )
+    .bind(decoder.name())
+    .bind(from.unwrap_or(0))
+    .bind(to.unwrap_or(i32::MAX))
+    .fetch_all(executor)
+    .await
+}
+
+/// Replaces the failures of `decoder` recorded for `block_nums` with `failures`, the blocks
+/// are decoded again as a whole so the old failures are either fixed or recorded again.
+/// Must be called in the transaction of the decoded rows.
+pub async fn replace_decode_failures(
+    tx: &mut Transaction<'_, Postgres>,
+    decoder: DecoderKind,
+    block_nums: &[i32],
+    failures: &[DecodeFailure],
+) -> Result<(), sqlx::Error> {
+    sqlx::query("delete from decode_failures where decoder = $1 and block_num = Any($2);")
+        .bind(decoder.name())
+        .bind(block_nums)
+        .execute(&mut *tx)
+        .await?;
+    if failures.is_empty() {
+        return Ok(());
+    }
+
+    let mut block_num_vec = vec![];
+    let mut storage_keys = vec![];
+    let mut storage_vals = vec![];
+    let mut errors = vec![];
+    for failure in failures {
+        block_num_vec.push(failure.block_num);
+        storage_keys.push(failure.storage_key.clone());
+        storage_vals.push(failure.storage_val.clone());
+        errors.push(failure.error.to_string());
+    }
+    sqlx::query(
+        "insert into decode_failures(decoder, block_num, storage_key, storage_value, error) select $1, * from unnest ($2, $3, $4, $5);",
+    )
+    .bind(decoder.name())
+    .bind(&block_num_vec)
+    .bind(&storage_keys)
+    .bind(&storage_vals)
+    .bind(&errors)
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
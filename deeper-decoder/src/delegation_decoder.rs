@@ -1,89 +1,459 @@
+use async_trait::async_trait;
 use desub_current::value::{Composite, Value};
-use desub_current::Metadata;
-use sp_core::crypto::AccountId32;
-use sp_runtime::MultiAddress;
-use std::collections::HashSet;
-
-pub fn get_delegation_changed_account_ids(ext: &str) -> HashSet<AccountId32> {
-    let mut account_ids: HashSet<AccountId32> = HashSet::new();
-
-    match serde_json::from_str::<Vec<crate::CurrentExtrinsic>>(ext) {
-        Ok(extrinsics) => {
-            for extrinsic in &extrinsics {
-                if extrinsic.current.call_data.pallet_name == "Staking"
-                    && ["delegate", "undelegate"]
-                        .contains(&extrinsic.current.call_data.ty.name().as_str())
-                {
-                    match extrinsic.current.signature.clone() {
-                        Some(signature_val) => match signature_val.address {
-                            MultiAddress::Id(account_id) => {
-                                account_ids.insert(account_id);
-                            }
-                            _ => {}
-                        },
-                        _ => {}
-                    }
-                }
-            }
-            account_ids
+use sp_core::crypto::{AccountId32, Ss58Codec};
+use sqlx::postgres::Postgres;
+use sqlx::types::Json;
+use sqlx::Transaction;
+use std::collections::HashMap;
+
+use crate::call_walker::Call;
+use crate::common::BlockMetadata;
+use crate::error::{DecodeError, DecodeFailure};
+use crate::legacy_decoder::LegacyExtrinsic;
+use crate::pallet_decoder::{Block, PalletDecoder};
+use crate::value_ext::ValueExt;
+
+/// Hex prefix of the `Staking.Delegators` storage keys.
+pub fn delegators_prefix() -> String {
+    hex::encode(crate::common::storage_prefix("Staking", "Delegators"))
+}
+
+/// What changed the `Staking.Delegators` entry of a delegator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DelegationAction {
+    Delegate,
+    Undelegate,
+    /// changed without a delegation call, e.g. by the era payout
+    Update,
+}
+
+impl DelegationAction {
+    /// value of the `action` column of `block_delegation`
+    pub fn name(&self) -> &'static str {
+        match self {
+            DelegationAction::Delegate => "delegate",
+            DelegationAction::Undelegate => "undelegate",
+            DelegationAction::Update => "update",
         }
-        Err(_) => account_ids,
     }
 }
 
-pub fn get_validators(storage_key: &str, storage_val: &str, meta: &Metadata) -> Vec<AccountId32> {
-    let val = crate::common::decode_storage(storage_key, storage_val, meta);
-    let mut res = vec![];
-    match val {
-        Value::Composite(Composite::Named(cn)) => {
-            for cn1 in &cn {
-                if cn1.0 == "delegated_validators" {
-                    match cn1.1.clone() {
-                        Value::Composite(Composite::Unnamed(cn2)) => match cn2[0].clone() {
-                            Value::Composite(Composite::Unnamed(cn3)) => {
-                                for cn3i in cn3 {
-                                    match cn3i.clone() {
-                                        Value::Composite(Composite::Unnamed(cn4)) => {
-                                            let addrs_result =
-                                                crate::common::decode_account_id(cn4.clone());
-                                            match addrs_result {
-                                                Ok(account_id) => res.push(account_id),
-                                                _ => {}
-                                            }
-                                        }
-                                        _ => {}
-                                    }
-                                }
-                            }
-                            _ => {}
-                        },
-                        _ => {
-                            return res;
-                        }
+/// `Staking.Delegators` entry of a delegator, the default is a removed entry.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DelegatorData {
+    pub delegated_validators: Vec<AccountId32>,
+    /// first era the delegator is not rewarded for yet
+    pub unrewarded_since: Option<u32>,
+    pub delegating: bool,
+}
+
+/// The delegators calling `Staking.delegate` or `Staking.undelegate` in a block, directly or
+/// wrapped in a batch, a proxy or a multisig call. The last call of a delegator wins.
+pub fn delegation_actions<'a>(
+    calls: &[Call],
+    legacy: impl IntoIterator<Item = &'a LegacyExtrinsic>,
+) -> HashMap<AccountId32, DelegationAction> {
+    let mut actions = HashMap::new();
+    let action_of = |name: &str| match name {
+        "delegate" => Some(DelegationAction::Delegate),
+        "undelegate" => Some(DelegationAction::Undelegate),
+        _ => None,
+    };
+
+    for call in calls {
+        if call.pallet != "Staking" {
+            continue;
+        }
+        if let (Some(action), Some(account_id)) = (action_of(call.name), call.signer()) {
+            actions.insert(account_id.clone(), action);
+        }
+    }
+    for extrinsic in legacy {
+        if extrinsic.legacy.call.module != "Staking" {
+            continue;
+        }
+        if let (Some(action), Some(account_id)) =
+            (action_of(&extrinsic.legacy.call.name), extrinsic.signer())
+        {
+            actions.insert(account_id, action);
+        }
+    }
+    actions
+}
+
+pub fn get_delegator_data(
+    storage_key: &str,
+    storage_val: &str,
+    meta: &BlockMetadata,
+) -> Result<DelegatorData, DecodeError> {
+    let data = crate::common::decode_storage(storage_key, storage_val, meta)?;
+    // `Vec<AccountId>`, the validators are read by their type for metadata v14
+    let validator_ty = match meta {
+        BlockMetadata::Current(_, keys) => keys
+            .value_type("Staking", "Delegators")
+            .and_then(|ty| {
+                crate::common::named_field_type(keys.types(), ty, "delegated_validators")
+            })
+            .and_then(|ty| crate::common::item_type(keys.types(), ty)),
+        BlockMetadata::Legacy(_) => None,
+    };
+    Ok(DelegatorData {
+        delegated_validators: data
+            .field("delegated_validators")?
+            .as_seq()?
+            .iter()
+            .map(|validator| crate::common::field_account_id(meta.types(), validator_ty, validator))
+            .collect::<Result<_, _>>()?,
+        unrewarded_since: match data.opt_field("unrewarded_since") {
+            Some(era) => option_u32(era)?,
+            None => None,
+        },
+        delegating: data.field("delegating")?.as_bool()?,
+    })
+}
+
+// `Option<EraIndex>`, decoded as a `None` or `Some(era)` variant, legacy json renders it as
+// `null` or the era
+fn option_u32(value: &Value) -> Result<Option<u32>, DecodeError> {
+    match value {
+        Value::Composite(Composite::Unnamed(values)) if values.is_empty() => Ok(None),
+        Value::Primitive(_) => Ok(Some(value.as_u32()?)),
+        Value::Variant(variant) => match (variant.name.as_str(), &variant.values) {
+            ("None", _) => Ok(None),
+            ("Some", Composite::Unnamed(values)) if values.len() == 1 => {
+                Ok(Some(values[0].as_u32()?))
+            }
+            _ => Err(DecodeError::TypeMismatch(format!(
+                "expect Option<u32>, got {:?}",
+                value
+            ))),
+        },
+        other => Err(DecodeError::TypeMismatch(format!(
+            "expect Option<u32>, got {:?}",
+            other
+        ))),
+    }
+}
+
+/// SS58 addresses of the validators added and removed between two validator lists.
+pub fn validator_diff(previous: &[String], current: &[String]) -> (Vec<String>, Vec<String>) {
+    let added = current
+        .iter()
+        .filter(|validator| !previous.contains(validator))
+        .cloned()
+        .collect();
+    let removed = previous
+        .iter()
+        .filter(|validator| !current.contains(validator))
+        .cloned()
+        .collect();
+    (added, removed)
+}
+
+/// A row of `block_delegation`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DelegationRow {
+    pub block_num: i32,
+    pub delegator: String,
+    pub action: DelegationAction,
+    pub validators: Vec<String>,
+    pub unrewarded_since: Option<u32>,
+    pub delegating: bool,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl DelegatorData {
+    pub fn validator_addresses(&self) -> Vec<String> {
+        self.delegated_validators
+            .iter()
+            .map(|validator| validator.to_ss58check())
+            .collect()
+    }
+}
+
+/// Writes the `Staking.Delegators` changes into `block_delegation`, with the validators
+/// added and removed since the previous change of the delegator.
+pub struct DelegationDecoder;
+
+#[async_trait]
+impl PalletDecoder for DelegationDecoder {
+    /// (block_num, delegator, action, delegator data)
+    type Row = (i32, String, DelegationAction, DelegatorData);
+
+    fn name(&self) -> &'static str {
+        "delegation"
+    }
+
+    fn storage_prefixes(&self) -> Vec<String> {
+        vec![delegators_prefix()]
+    }
+
+    fn calls(&self) -> Vec<(&'static str, &'static str)> {
+        vec![("Staking", "delegate"), ("Staking", "undelegate")]
+    }
+
+    fn decode(&self, block: &Block, rows: &mut Vec<Self::Row>, failures: &mut Vec<DecodeFailure>) {
+        let block_num = block.block_num();
+        // every change of a delegator writes its `Staking.Delegators` entry, the calls of the
+        // block tell a delegation from an undelegation
+        let actions = delegation_actions(&block.calls(), block.legacy_calls());
+        for (storage_key, storage_str) in block.storage() {
+            let addr = match crate::common::map_key_account_id(
+                storage_key,
+                "Staking",
+                "Delegators",
+                block.meta(),
+            ) {
+                Ok(addr) => addr,
+                Err(err) => {
+                    failures.push(DecodeFailure::storage(
+                        block_num,
+                        storage_key,
+                        storage_str,
+                        err,
+                    ));
+                    continue;
+                }
+            };
+            let data = if storage_str.is_empty() {
+                // the entry was removed
+                DelegatorData::default()
+            } else {
+                match get_delegator_data(storage_key, storage_str, block.meta()) {
+                    Ok(data) => data,
+                    Err(err) => {
+                        failures.push(DecodeFailure::storage(
+                            block_num,
+                            storage_key,
+                            storage_str,
+                            err,
+                        ));
+                        continue;
                     }
                 }
+            };
+            let action = match actions.get(&addr) {
+                Some(action) => *action,
+                None if storage_str.is_empty() => DelegationAction::Undelegate,
+                None => DelegationAction::Update,
+            };
+            rows.push((block_num, addr.to_ss58check(), action, data));
+        }
+    }
+
+    async fn write(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        mut rows: Vec<Self::Row>,
+    ) -> Result<(), sqlx::Error> {
+        // the row before each change is the row of the delegator with the highest block
+        // below it, decoded earlier or in this batch. The row after the last change of the
+        // batch (after a retry or an earlier run) is diffed again against it.
+        let delegators: Vec<String> = rows.iter().map(|row| row.1.clone()).collect();
+        let block_nums: Vec<i32> = rows.iter().map(|row| row.0).collect();
+        let previous = neighbour_rows(tx, &delegators, &block_nums, Neighbour::Previous).await?;
+        let next = neighbour_rows(tx, &delegators, &block_nums, Neighbour::Next).await?;
+        rows.sort_by(|a, b| (&a.1, a.0).cmp(&(&b.1, b.0)));
+        let mut in_batch: HashMap<String, (i32, Vec<String>)> = HashMap::new();
+        let mut to_insert = vec![];
+        let mut to_update: HashMap<(String, i32), (Vec<String>, Vec<String>)> = HashMap::new();
+
+        for (block_num, delegator, action, data) in rows {
+            let validators = data.validator_addresses();
+            let key = (delegator.clone(), block_num);
+            // on a tie the row of the batch replaces the one decoded earlier
+            let previous_validators = previous
+                .get(&key)
+                .into_iter()
+                .chain(in_batch.get(&delegator))
+                .max_by_key(|(previous_block, _)| *previous_block)
+                .map_or(&[][..], |(_, validators)| validators.as_slice());
+            let (added, removed) = validator_diff(previous_validators, &validators);
+            // rows are sorted, a later change of the batch below the same next row overrides
+            if let Some((next_block, next_validators)) = next.get(&key) {
+                to_update.insert(
+                    (delegator.clone(), *next_block),
+                    validator_diff(&validators, next_validators),
+                );
             }
-            res
+            to_update.remove(&key);
+            in_batch.insert(delegator.clone(), (block_num, validators.clone()));
+            to_insert.push(DelegationRow {
+                block_num,
+                delegator,
+                action,
+                validators,
+                unrewarded_since: data.unrewarded_since,
+                delegating: data.delegating,
+                added,
+                removed,
+            });
         }
-        _ => res,
+        insert_delegations(tx, to_insert).await?;
+        update_delegation_diffs(tx, to_update).await
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Neighbour {
+    Previous,
+    Next,
+}
+
+// (block, validators) of the row of each (delegator, block) with the highest block below it or
+// with the lowest block above it
+async fn neighbour_rows(
+    tx: &mut Transaction<'_, Postgres>,
+    delegators: &[String],
+    block_nums: &[i32],
+    neighbour: Neighbour,
+) -> Result<HashMap<(String, i32), (i32, Vec<String>)>, sqlx::Error> {
+    let (filter, order) = match neighbour {
+        Neighbour::Previous => ("d.block_num < t.block_num", "DESC"),
+        Neighbour::Next => ("d.block_num > t.block_num", "ASC"),
+    };
+    let rows: Vec<(String, i32, i32, Json<Vec<String>>)> = sqlx::query_as(&format!(
+        r#"SELECT t.delegator, t.block_num, n.block_num, n.validators
+        FROM UNNEST($1::varchar[], $2::integer[]) AS t(delegator, block_num)
+        CROSS JOIN LATERAL (SELECT block_num, validators FROM block_delegation d
+            WHERE d.delegator = t.delegator AND {}
+            ORDER BY d.block_num {} LIMIT 1) n;"#,
+        filter, order
+    ))
+    .bind(delegators)
+    .bind(block_nums)
+    .fetch_all(&mut *tx)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(delegator, block_num, neighbour_block, validators)| {
+            ((delegator, block_num), (neighbour_block, validators.0))
+        })
+        .collect())
+}
+
+// (added, removed) of the rows following the changes of a batch
+async fn update_delegation_diffs(
+    tx: &mut Transaction<'_, Postgres>,
+    diffs: HashMap<(String, i32), (Vec<String>, Vec<String>)>,
+) -> Result<(), sqlx::Error> {
+    if diffs.is_empty() {
+        return Ok(());
     }
+    let mut to_update_delegators = vec![];
+    let mut to_update_block_nums: Vec<i32> = vec![];
+    let mut to_update_added = vec![];
+    let mut to_update_removed = vec![];
+    diffs
+        .into_iter()
+        .for_each(|((delegator, block_num), (added, removed))| {
+            to_update_delegators.push(delegator);
+            to_update_block_nums.push(block_num);
+            to_update_added.push(Json(added));
+            to_update_removed.push(Json(removed));
+        });
+    sqlx::query(
+        r#"UPDATE block_delegation d SET added = u.added, removed = u.removed
+        FROM UNNEST($1::varchar[], $2::integer[], $3::jsonb[], $4::jsonb[])
+            AS u(delegator, block_num, added, removed)
+        WHERE d.delegator = u.delegator AND d.block_num = u.block_num;"#,
+    )
+    .bind(&to_update_delegators)
+    .bind(&to_update_block_nums)
+    .bind(&to_update_added)
+    .bind(&to_update_removed)
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+// one statement per batch, a delegation heavy era used to take one round trip per delegator
+async fn insert_delegations(
+    tx: &mut Transaction<'_, Postgres>,
+    rows: Vec<DelegationRow>,
+) -> Result<(), sqlx::Error> {
+    if rows.is_empty() {
+        return Ok(());
+    }
+    let mut to_insert_block_nums: Vec<i32> = vec![];
+    let mut to_insert_delegators = vec![];
+    let mut to_insert_actions = vec![];
+    let mut to_insert_validators = vec![];
+    let mut to_insert_unrewarded_since = vec![];
+    let mut to_insert_delegating = vec![];
+    let mut to_insert_added = vec![];
+    let mut to_insert_removed = vec![];
+    rows.into_iter().for_each(|row| {
+        to_insert_block_nums.push(row.block_num);
+        to_insert_delegators.push(row.delegator);
+        to_insert_actions.push(row.action.name());
+        to_insert_validators.push(Json(row.validators));
+        to_insert_unrewarded_since.push(row.unrewarded_since.map(i64::from));
+        to_insert_delegating.push(row.delegating);
+        to_insert_added.push(Json(row.added));
+        to_insert_removed.push(Json(row.removed));
+    });
+    sqlx::query(
+        r#"INSERT INTO block_delegation (block_num, delegator, action, validators, unrewarded_since,
+            delegating, added, removed)
+        SELECT * FROM UNNEST($1::integer[], $2::varchar[], $3::varchar[], $4::jsonb[], $5::bigint[],
+            $6::boolean[], $7::jsonb[], $8::jsonb[])
+        ON CONFLICT (block_num, delegator) DO UPDATE SET action = EXCLUDED.action,
+            validators = EXCLUDED.validators, unrewarded_since = EXCLUDED.unrewarded_since,
+            delegating = EXCLUDED.delegating, added = EXCLUDED.added, removed = EXCLUDED.removed;"#,
+    )
+    .bind(&to_insert_block_nums)
+    .bind(&to_insert_delegators)
+    .bind(&to_insert_actions)
+    .bind(&to_insert_validators)
+    .bind(&to_insert_unrewarded_since)
+    .bind(&to_insert_delegating)
+    .bind(&to_insert_added)
+    .bind(&to_insert_removed)
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use sp_core::crypto::Ss58Codec;
-
     use crate::common::deeper_metadata;
 
     use super::*;
 
     #[test]
     fn test_get_delegator() {
-        let res = get_validators("5f3e4907f716ac89b6347d15ececedcae1c5df6d2773f08c7b6b1b6d0139c22a3594ef778a4003043f6d977057644d65a88b59afe73f0e769e4f9d85cd40fd13f0874446f22d2ab6780f9cb89059307e", "a88b59afe73f0e769e4f9d85cd40fd13f0874446f22d2ab6780f9cb89059307e04be5ddb1579b72e84524fc29e78609e3caf42e85aa118ebfe0b0ad404b5bdd25f010100000001", &deeper_metadata());
+        let res = get_delegator_data("5f3e4907f716ac89b6347d15ececedcae1c5df6d2773f08c7b6b1b6d0139c22a3594ef778a4003043f6d977057644d65a88b59afe73f0e769e4f9d85cd40fd13f0874446f22d2ab6780f9cb89059307e", "a88b59afe73f0e769e4f9d85cd40fd13f0874446f22d2ab6780f9cb89059307e04be5ddb1579b72e84524fc29e78609e3caf42e85aa118ebfe0b0ad404b5bdd25f010100000001", &deeper_metadata()).unwrap();
         let alice_stash =
             AccountId32::from_ss58check("5GNJqTPyNqANBkUVMN1LPPrxXnFouWXoe2wNSmmEoLctxiZY")
                 .unwrap();
-        assert_eq!(res, vec![alice_stash]);
+        assert_eq!(
+            res,
+            DelegatorData {
+                delegated_validators: vec![alice_stash],
+                unrewarded_since: Some(1),
+                delegating: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_validator_diff() {
+        let validators =
+            |names: &[&str]| -> Vec<String> { names.iter().map(|name| name.to_string()).collect() };
+
+        assert_eq!(
+            validator_diff(&validators(&["a", "b"]), &validators(&["b", "c"])),
+            (validators(&["c"]), validators(&["a"]))
+        );
+        // undelegate, the entry is removed
+        assert_eq!(
+            validator_diff(&validators(&["a"]), &[]),
+            (vec![], validators(&["a"]))
+        );
     }
 
     #[test]
@@ -273,10 +643,214 @@ mod tests {
               }
             }
           ]"##;
-        let account_ids = get_delegation_changed_account_ids(s);
+        let extrinsics: Vec<crate::CurrentExtrinsic> = serde_json::from_str(s).unwrap();
+        let meta = deeper_metadata();
+        let calls: Vec<Call> = extrinsics
+            .iter()
+            .flat_map(|extrinsic| crate::call_walker::extrinsic_calls(extrinsic, &meta, &[]))
+            .collect();
+        let actions = delegation_actions(&calls, std::iter::empty::<&LegacyExtrinsic>());
         let dest = AccountId32::from_ss58check("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
             .unwrap();
 
-        assert!(account_ids.contains(&dest));
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions.get(&dest), Some(&DelegationAction::Delegate));
+    }
+
+    // run with `DATABASE_URL=... cargo test -- --ignored test_write_diffs_next_delegation`
+    #[async_std::test]
+    #[ignore]
+    async fn test_write_diffs_next_delegation() {
+        const FIRST_BLOCK: i32 = 2_000_000_000;
+
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL is set");
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&url)
+            .await
+            .unwrap();
+        let alice = AccountId32::new([1; 32]);
+        let bob = AccountId32::new([2; 32]);
+        let delegator = String::from("delegator");
+        let change = |block_num: i32, validators: Vec<AccountId32>| {
+            let data = DelegatorData {
+                delegated_validators: validators,
+                unrewarded_since: None,
+                delegating: true,
+            };
+            (
+                block_num,
+                delegator.clone(),
+                DelegationAction::Delegate,
+                data,
+            )
+        };
+
+        let mut tx = pool.begin().await.unwrap();
+        DelegationDecoder
+            .write(
+                &mut tx,
+                vec![
+                    change(FIRST_BLOCK, vec![alice.clone()]),
+                    change(FIRST_BLOCK + 20, vec![alice.clone(), bob.clone()]),
+                ],
+            )
+            .await
+            .unwrap();
+        // a change between them decoded later, e.g. by `retry`
+        DelegationDecoder
+            .write(&mut tx, vec![change(FIRST_BLOCK + 10, vec![bob.clone()])])
+            .await
+            .unwrap();
+        let diffs: Vec<(i32, Json<Vec<String>>, Json<Vec<String>>)> = sqlx::query_as(
+            "select block_num, added, removed from block_delegation where delegator = $1 and block_num >= $2 order by block_num;",
+        )
+        .bind(&delegator)
+        .bind(FIRST_BLOCK)
+        .fetch_all(&mut tx)
+        .await
+        .unwrap();
+        tx.rollback().await.unwrap();
+
+        let (alice, bob) = (alice.to_ss58check(), bob.to_ss58check());
+        let diffs: Vec<(i32, Vec<String>, Vec<String>)> = diffs
+            .into_iter()
+            .map(|(block_num, added, removed)| (block_num, added.0, removed.0))
+            .collect();
+        assert_eq!(
+            diffs,
+            vec![
+                (FIRST_BLOCK, vec![alice.clone()], vec![]),
+                (FIRST_BLOCK + 10, vec![bob], vec![alice.clone()]),
+                (FIRST_BLOCK + 20, vec![alice], vec![]),
+            ]
+        );
+    }
+
+    // benchmark of the delegation writes, run with
+    // `DATABASE_URL=... cargo test --release -- --ignored --nocapture bench_insert_delegations`
+    #[async_std::test]
+    #[ignore]
+    async fn bench_insert_delegations() {
+        const FIRST_BLOCK: i32 = 2_000_000_000;
+        const ROWS: i32 = 5000;
+
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL is set");
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&url)
+            .await
+            .unwrap();
+        let validator = String::from("5GNJqTPyNqANBkUVMN1LPPrxXnFouWXoe2wNSmmEoLctxiZY");
+        let rows: Vec<DelegationRow> = (0..ROWS)
+            .map(|i| DelegationRow {
+                block_num: FIRST_BLOCK + i / 100,
+                delegator: format!("delegator-{}", i % 100),
+                action: DelegationAction::Delegate,
+                validators: vec![validator.clone()],
+                unrewarded_since: Some(1),
+                delegating: true,
+                added: vec![validator.clone()],
+                removed: vec![],
+            })
+            .collect();
+
+        // one statement per row, as delegations used to be written
+        let mut tx = pool.begin().await.unwrap();
+        let start = std::time::Instant::now();
+        for row in &rows {
+            sqlx::query(
+                "insert into block_delegation(block_num, delegator, action, validators, unrewarded_since, delegating, added, removed) values ($1, $2, $3, $4, $5, $6, $7, $8) on conflict (block_num, delegator) do nothing;",
+            )
+            .bind(row.block_num)
+            .bind(&row.delegator)
+            .bind(row.action.name())
+            .bind(Json(&row.validators))
+            .bind(row.unrewarded_since.map(i64::from))
+            .bind(row.delegating)
+            .bind(Json(&row.added))
+            .bind(Json(&row.removed))
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        }
+        let per_row = start.elapsed();
+        let inserted_per_row = written_delegations(&mut tx, FIRST_BLOCK).await;
+        tx.rollback().await.unwrap();
+
+        let mut tx = pool.begin().await.unwrap();
+        let start = std::time::Instant::now();
+        insert_delegations(&mut tx, rows.clone()).await.unwrap();
+        let bulk = start.elapsed();
+        let inserted_bulk = written_delegations(&mut tx, FIRST_BLOCK).await;
+        tx.rollback().await.unwrap();
+
+        // timings depend on the machine, they are reported and not compared
+        println!(
+            "{} delegations: {:?} row by row, {:?} with unnest",
+            ROWS, per_row, bulk
+        );
+        let mut expected = rows;
+        expected.sort_by(|a, b| (a.block_num, &a.delegator).cmp(&(b.block_num, &b.delegator)));
+        assert_eq!(inserted_per_row, expected);
+        assert_eq!(inserted_bulk, expected);
+    }
+
+    // (block_num, delegator, action, validators, unrewarded_since, delegating, added, removed)
+    type WrittenDelegation = (
+        i32,
+        String,
+        String,
+        Json<Vec<String>>,
+        Option<i64>,
+        bool,
+        Json<Vec<String>>,
+        Json<Vec<String>>,
+    );
+
+    async fn written_delegations(
+        tx: &mut Transaction<'_, Postgres>,
+        first_block: i32,
+    ) -> Vec<DelegationRow> {
+        let rows: Vec<WrittenDelegation> = sqlx::query_as(
+            "select block_num, delegator, action, validators, unrewarded_since, delegating, added, removed from block_delegation where block_num >= $1;",
+        )
+        .bind(first_block)
+        .fetch_all(&mut *tx)
+        .await
+        .unwrap();
+        let mut written: Vec<DelegationRow> = rows
+            .into_iter()
+            .map(
+                |(
+                    block_num,
+                    delegator,
+                    action,
+                    validators,
+                    unrewarded_since,
+                    delegating,
+                    added,
+                    removed,
+                )| {
+                    DelegationRow {
+                        block_num,
+                        delegator,
+                        action: match action.as_str() {
+                            "delegate" => DelegationAction::Delegate,
+                            "undelegate" => DelegationAction::Undelegate,
+                            "update" => DelegationAction::Update,
+                            other => panic!("unknown action {}", other),
+                        },
+                        validators: validators.0,
+                        unrewarded_since: unrewarded_since.map(|era| era as u32),
+                        delegating,
+                        added: added.0,
+                        removed: removed.0,
+                    }
+                },
+            )
+            .collect();
+        written.sort_by(|a, b| (a.block_num, &a.delegator).cmp(&(b.block_num, &b.delegator)));
+        written
     }
 }
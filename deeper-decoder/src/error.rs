@@ -0,0 +1,83 @@
+use std::{error::Error, fmt};
+
+/// Why a storage entry or a block could not be decoded. A decode error only skips the
+/// item it happened on, database errors still abort the batch.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeError {
+    /// storage key or value is not valid hex
+    BadHex(hex::FromHexError),
+    /// storage key doesn't match any entry of the metadata
+    UnknownStorageKey(String),
+    /// value can't be decoded as the type in the metadata, or the decoded value
+    /// doesn't have the shape the decoder expects
+    TypeMismatch(String),
+    /// no metadata for the spec version of the block
+    MissingMetadata(i32),
+    /// the metadata of the spec version can't be parsed
+    InvalidMetadata(i32, String),
+    /// the bytes are not a valid 32 bytes account id
+    InvalidAccountId,
+    /// an evm address, deeper-chain doesn't map them to substrate accounts
+    EvmAddress,
+    /// desub-legacy failed to decode a pre-v14 storage entry
+    Legacy(String),
+}
+
+impl Error for DecodeError {}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::BadHex(err) => write!(f, "bad hex: {}", err),
+            DecodeError::UnknownStorageKey(key) => write!(f, "unknown storage key: {}", key),
+            DecodeError::TypeMismatch(msg) => write!(f, "type mismatch: {}", msg),
+            DecodeError::MissingMetadata(spec) => write!(f, "missing metadata of spec {}", spec),
+            DecodeError::InvalidMetadata(spec, msg) => {
+                write!(f, "invalid metadata of spec {}: {}", spec, msg)
+            }
+            DecodeError::InvalidAccountId => write!(f, "invalid account id"),
+            DecodeError::EvmAddress => write!(f, "evm address without substrate account"),
+            DecodeError::Legacy(msg) => write!(f, "legacy decode failed: {}", msg),
+        }
+    }
+}
+
+impl From<hex::FromHexError> for DecodeError {
+    fn from(err: hex::FromHexError) -> Self {
+        DecodeError::BadHex(err)
+    }
+}
+
+/// An item skipped by a decoder.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodeFailure {
+    pub block_num: i32,
+    pub storage_key: Option<String>,
+    pub storage_val: Option<String>,
+    pub error: DecodeError,
+}
+
+impl DecodeFailure {
+    pub fn block(block_num: i32, error: DecodeError) -> Self {
+        Self {
+            block_num,
+            storage_key: None,
+            storage_val: None,
+            error,
+        }
+    }
+
+    pub fn storage(
+        block_num: i32,
+        storage_key: &str,
+        storage_val: &str,
+        error: DecodeError,
+    ) -> Self {
+        Self {
+            block_num,
+            storage_key: Some(storage_key.to_string()),
+            storage_val: Some(storage_val.to_string()),
+            error,
+        }
+    }
+}
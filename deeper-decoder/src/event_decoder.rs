@@ -1,13 +1,439 @@
-use desub_current::value::{Composite, Value};
-use desub_current::Metadata;
+use async_trait::async_trait;
+use desub_current::value::{Composite, Primitive, Value};
+use sp_core::crypto::{AccountId32, Ss58Codec};
+use sqlx::postgres::Postgres;
+use sqlx::types::Json;
+use sqlx::Transaction;
+
+use crate::common::BlockMetadata;
+use crate::error::{DecodeError, DecodeFailure};
+use crate::pallet_decoder::{Block, PalletDecoder};
+use crate::value_ext::ValueExt;
 
 // TODO: use jsonb to store event detail may cause performance issue, in the future
 // we may need to come up with a new way.
 // the old style is to match events we care, but that's too costy.
-pub fn decode_event(storage_key: &str, storage_val: &str, meta: &Metadata) -> Vec<Value> {
-    let event_value = crate::common::decode_storage(storage_key, storage_val, meta);
-    match event_value {
-        Value::Composite(Composite::Unnamed(events)) => events,
-        _ => vec![],
+pub fn decode_event(
+    storage_key: &str,
+    storage_val: &str,
+    meta: &BlockMetadata,
+) -> Result<Vec<Value>, DecodeError> {
+    match crate::common::decode_storage(storage_key, storage_val, meta)? {
+        Value::Composite(Composite::Unnamed(events)) => match meta {
+            BlockMetadata::Current(..) => Ok(events),
+            BlockMetadata::Legacy(_) => Ok(events
+                .into_iter()
+                .map(crate::legacy_decoder::event_record)
+                .collect()),
+        },
+        other => Err(DecodeError::TypeMismatch(format!(
+            "expect Vec<EventRecord>, got {:?}",
+            other
+        ))),
+    }
+}
+
+/// An `EventRecord` split into the columns of `block_event`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventRow {
+    pub pallet: String,
+    pub variant: String,
+    /// `ApplyExtrinsic`, `Finalization` or `Initialization`
+    pub phase: String,
+    /// index of the extrinsic which emitted the event, for the `ApplyExtrinsic` phase
+    pub extrinsic_index: Option<u32>,
+    /// hex encoded topics
+    pub topics: Vec<String>,
+    /// event fields, as rendered in `info`
+    pub args: serde_json::Value,
+    /// the whole record
+    pub info: serde_json::Value,
+}
+
+pub fn event_row(record: &Value, meta: &BlockMetadata) -> Result<EventRow, DecodeError> {
+    let (pallet, variant, _) = event_variant(record).ok_or_else(|| {
+        DecodeError::TypeMismatch(format!("expect EventRecord, got {:?}", record))
+    })?;
+    let (phase, extrinsic_index) = event_phase(record.field("phase")?)?;
+    let topics = match record.opt_field("topics") {
+        Some(topics) => topics
+            .as_seq()?
+            .iter()
+            .map(|topic| {
+                hash_bytes(topic)
+                    .map(|bytes| format!("0x{}", hex::encode(bytes)))
+                    .ok_or_else(|| {
+                        DecodeError::TypeMismatch(format!("expect H256 topic, got {:?}", topic))
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => vec![],
+    };
+    let info = event_to_json(record, meta);
+    let args = info
+        .pointer("/event/values/0/values")
+        .cloned()
+        .unwrap_or(serde_json::Value::Null);
+
+    Ok(EventRow {
+        pallet: pallet.to_string(),
+        variant: variant.to_string(),
+        phase,
+        extrinsic_index,
+        topics,
+        args,
+        info,
+    })
+}
+
+// `Phase` is an enum, legacy json renders it as `{"ApplyExtrinsic": 1}` or `"Finalization"`
+pub fn event_phase(phase: &Value) -> Result<(String, Option<u32>), DecodeError> {
+    let (name, values): (&str, Vec<&Value>) = match phase {
+        Value::Variant(variant) => (
+            &variant.name,
+            match &variant.values {
+                Composite::Named(values) => values.iter().map(|(_, value)| value).collect(),
+                Composite::Unnamed(values) => values.iter().collect(),
+            },
+        ),
+        Value::Composite(Composite::Named(values)) if values.len() == 1 => {
+            (&values[0].0, vec![&values[0].1])
+        }
+        Value::Primitive(Primitive::Str(name)) => (name, vec![]),
+        other => {
+            return Err(DecodeError::TypeMismatch(format!(
+                "expect Phase, got {:?}",
+                other
+            )))
+        }
+    };
+    let extrinsic_index = match (name, values.as_slice()) {
+        ("ApplyExtrinsic", [index]) => Some(index.as_u32()?),
+        _ => None,
+    };
+    Ok((name.to_string(), extrinsic_index))
+}
+
+// `H256([u8; 32])`, possibly wrapped in unnamed composites
+fn hash_bytes(value: &Value) -> Option<Vec<u8>> {
+    match value {
+        Value::Composite(Composite::Unnamed(values)) if values.len() == 1 => hash_bytes(&values[0]),
+        Value::Composite(Composite::Unnamed(values)) => crate::common::value_bytes(values),
+        _ => None,
+    }
+}
+
+/// Every account id found in the rendered event `args`, with the path of the field it was
+/// found in, e.g. `who` for named fields or `0` for the first unnamed one. Account ids are
+/// already SS58 strings in the rendered json.
+pub fn event_accounts(args: &serde_json::Value) -> Vec<(String, String)> {
+    let mut accounts = vec![];
+    collect_accounts(args, "", &mut accounts);
+    accounts.sort();
+    accounts.dedup();
+    accounts
+}
+
+fn collect_accounts(value: &serde_json::Value, path: &str, accounts: &mut Vec<(String, String)>) {
+    let child_path = |key: &str| {
+        if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", path, key)
+        }
+    };
+    match value {
+        serde_json::Value::String(addr) => {
+            if AccountId32::from_ss58check(addr).is_ok() {
+                accounts.push((addr.clone(), path.to_string()));
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for (index, value) in values.iter().enumerate() {
+                collect_accounts(value, &child_path(&index.to_string()), accounts);
+            }
+        }
+        serde_json::Value::Object(values) => {
+            for (key, value) in values {
+                // enum variants are rendered as `{"name": ..., "values": ...}`, the variant
+                // name is not a field
+                if key == "name" && values.contains_key("values") {
+                    continue;
+                }
+                let path = if key == "values" && values.contains_key("name") {
+                    path.to_string()
+                } else {
+                    child_path(key)
+                };
+                collect_accounts(value, &path, accounts);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Renders an `EventRecord` as json with account ids in SS58 and byte arrays in hex.
+pub fn event_to_json(record: &Value, meta: &BlockMetadata) -> serde_json::Value {
+    match meta {
+        BlockMetadata::Current(_, keys) => match keys.event_record_type() {
+            Some(ty) => crate::value_json::to_typed_json(keys.types(), ty, record),
+            None => crate::value_json::to_json(record),
+        },
+        BlockMetadata::Legacy(_) => crate::value_json::to_json(record),
+    }
+}
+
+/// Splits an `EventRecord` into (pallet, event name, event fields).
+pub fn event_variant(record: &Value) -> Option<(&str, &str, Vec<&Value>)> {
+    let (pallet, inner) = match record.opt_field("event")? {
+        Value::Variant(pallet) => match &pallet.values {
+            Composite::Unnamed(values) if values.len() == 1 => (pallet.name.as_str(), &values[0]),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    match inner {
+        Value::Variant(event) => {
+            let fields = match &event.values {
+                Composite::Named(values) => values.iter().map(|(_, value)| value).collect(),
+                Composite::Unnamed(values) => values.iter().collect(),
+            };
+            Some((pallet, event.name.as_str(), fields))
+        }
+        _ => None,
+    }
+}
+
+/// Type ids of the fields of the `pallet.event` event, from the `EventRecord` type of the
+/// metadata. Legacy metadata has no type registry.
+pub fn event_field_types(meta: &BlockMetadata, pallet: &str, event: &str) -> Option<Vec<u32>> {
+    let (types, record_ty) = match meta {
+        BlockMetadata::Current(_, keys) => (keys.types(), keys.event_record_type()?),
+        BlockMetadata::Legacy(_) => return None,
+    };
+    // `RuntimeEvent` has one variant per pallet wrapping the `Event` enum of the pallet
+    let runtime_event_ty = crate::common::named_field_type(types, record_ty, "event")?;
+    let pallet_event_ty =
+        *crate::common::variant_field_types(types, runtime_event_ty, pallet)?.first()?;
+    crate::common::variant_field_types(types, pallet_event_ty, event)
+}
+
+/// Reads the `index`th field of a `pallet.event` event as an account id, by its type for
+/// metadata v14.
+pub fn event_account_id(
+    meta: &BlockMetadata,
+    pallet: &str,
+    event: &str,
+    index: usize,
+    value: &Value,
+) -> Result<AccountId32, DecodeError> {
+    let ty = event_field_types(meta, pallet, event).and_then(|tys| tys.get(index).copied());
+    crate::common::field_account_id(meta.types(), ty, value)
+}
+
+/// Writes every event into `block_event` and the accounts they name into `account_event`.
+pub struct EventDecoder;
+
+#[async_trait]
+impl PalletDecoder for EventDecoder {
+    /// (block_num, event_index, event)
+    type Row = (i32, i32, EventRow);
+
+    fn name(&self) -> &'static str {
+        "event"
+    }
+
+    fn events(&self) -> Vec<(&'static str, &'static str)> {
+        vec![("*", "*")]
+    }
+
+    fn decode(&self, block: &Block, rows: &mut Vec<Self::Row>, failures: &mut Vec<DecodeFailure>) {
+        for (event_index, record) in block.events() {
+            match event_row(record, block.meta()) {
+                Ok(event) => rows.push((block.block_num(), event_index as i32, event)),
+                Err(err) => failures.push(block.event_failure(err)),
+            }
+        }
+    }
+
+    async fn write(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        rows: Vec<Self::Row>,
+    ) -> Result<(), sqlx::Error> {
+        // insert many rows
+        // https://github.com/launchbadge/sqlx/issues/294#issuecomment-830409187
+        let mut to_insert_block_nums: Vec<i32> = vec![];
+        let mut to_insert_event_indexes: Vec<i32> = vec![];
+        let mut to_insert_pallets = vec![];
+        let mut to_insert_variants = vec![];
+        let mut to_insert_phases = vec![];
+        let mut to_insert_extrinsic_indexes = vec![];
+        // arrays of arrays can't be unnested, topics are bound as json
+        let mut to_insert_topics = vec![];
+        let mut to_insert_args = vec![];
+        let mut to_insert_infos: Vec<Json<serde_json::Value>> = vec![];
+        let mut account_block_nums = vec![];
+        let mut account_event_indexes = vec![];
+        let mut account_addrs = vec![];
+        let mut account_roles = vec![];
+        rows.into_iter()
+            .for_each(|(block_num, event_index, event)| {
+                for (addr, role) in event_accounts(&event.args) {
+                    account_block_nums.push(block_num);
+                    account_event_indexes.push(event_index);
+                    account_addrs.push(addr);
+                    account_roles.push(role);
+                }
+                to_insert_block_nums.push(block_num);
+                to_insert_event_indexes.push(event_index);
+                to_insert_pallets.push(event.pallet);
+                to_insert_variants.push(event.variant);
+                to_insert_phases.push(event.phase);
+                to_insert_extrinsic_indexes.push(event.extrinsic_index.map(i64::from));
+                to_insert_topics.push(Json(event.topics));
+                to_insert_args.push(Json(event.args));
+                to_insert_infos.push(Json(event.info));
+            });
+        sqlx::query(
+            r#"INSERT INTO block_event (block_num, event_index, pallet, variant, phase, extrinsic_index, topics, args, info)
+            SELECT block_num, event_index, pallet, variant, phase, extrinsic_index,
+                ARRAY(SELECT jsonb_array_elements_text(topics)), args, info
+            FROM UNNEST($1::integer[], $2::integer[], $3::varchar[], $4::varchar[], $5::varchar[], $6::bigint[], $7::jsonb[], $8::jsonb[], $9::jsonb[])
+                AS t(block_num, event_index, pallet, variant, phase, extrinsic_index, topics, args, info)
+            ON CONFLICT (block_num, event_index) DO UPDATE SET pallet = EXCLUDED.pallet,
+                variant = EXCLUDED.variant, phase = EXCLUDED.phase,
+                extrinsic_index = EXCLUDED.extrinsic_index, topics = EXCLUDED.topics,
+                args = EXCLUDED.args, info = EXCLUDED.info;"#,
+        )
+        .bind(&to_insert_block_nums)
+        .bind(&to_insert_event_indexes)
+        .bind(&to_insert_pallets)
+        .bind(&to_insert_variants)
+        .bind(&to_insert_phases)
+        .bind(&to_insert_extrinsic_indexes)
+        .bind(&to_insert_topics)
+        .bind(&to_insert_args)
+        .bind(&to_insert_infos)
+        .execute(&mut *tx)
+        .await?;
+
+        // per account index of the events, for account history queries
+        sqlx::query(
+            r#"INSERT INTO account_event (block_num, event_index, address, role)
+            SELECT * FROM UNNEST($1, $2, $3, $4)
+            ON CONFLICT (block_num, event_index, address, role) DO NOTHING;"#,
+        )
+        .bind(&account_block_nums)
+        .bind(&account_event_indexes)
+        .bind(&account_addrs)
+        .bind(&account_roles)
+        .execute(&mut *tx)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::variant;
+
+    #[test]
+    fn test_event_row() {
+        let record = Value::Composite(Composite::Named(vec![
+            (
+                String::from("phase"),
+                variant(
+                    "ApplyExtrinsic",
+                    Composite::Unnamed(vec![Value::Primitive(Primitive::U32(2))]),
+                ),
+            ),
+            (
+                String::from("event"),
+                variant(
+                    "Timestamp",
+                    Composite::Unnamed(vec![variant(
+                        "Set",
+                        Composite::Unnamed(vec![Value::Primitive(Primitive::U64(7))]),
+                    )]),
+                ),
+            ),
+            (
+                String::from("topics"),
+                Value::Composite(Composite::Unnamed(vec![Value::Composite(
+                    Composite::Unnamed(vec![Value::Composite(Composite::Unnamed(vec![
+                        Value::Primitive(Primitive::U8(0xab));
+                        32
+                    ]))]),
+                )])),
+            ),
+        ]));
+        let meta = crate::common::deeper_metadata();
+
+        let row = event_row(&record, &meta).unwrap();
+        assert_eq!(row.pallet, "Timestamp");
+        assert_eq!(row.variant, "Set");
+        assert_eq!(row.phase, "ApplyExtrinsic");
+        assert_eq!(row.extrinsic_index, Some(2));
+        assert_eq!(row.topics, vec![format!("0x{}", "ab".repeat(32))]);
+    }
+
+    #[test]
+    fn test_event_account_id() {
+        let addr = AccountId32::from_ss58check("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
+            .unwrap();
+        let account = crate::test_util::account_value(&addr);
+        let meta = crate::common::deeper_metadata();
+
+        // `CreditUpdateSuccess(AccountId, u64)`
+        assert_eq!(
+            event_field_types(&meta, "Credit", "CreditUpdateSuccess").map(|tys| tys.len()),
+            Some(2)
+        );
+        assert_eq!(
+            event_account_id(&meta, "Credit", "CreditUpdateSuccess", 0, &account),
+            Ok(addr)
+        );
+        assert_eq!(
+            event_account_id(&meta, "Credit", "CreditUpdateSuccess", 1, &account),
+            Err(DecodeError::InvalidAccountId)
+        );
+        assert!(matches!(
+            event_account_id(&meta, "Credit", "Unknown", 0, &account),
+            Err(DecodeError::TypeMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_event_accounts() {
+        let who = "5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ";
+        let to = "5GNJqTPyNqANBkUVMN1LPPrxXnFouWXoe2wNSmmEoLctxiZY";
+        let args = serde_json::json!([
+            who,
+            { "to": to, "amount": 10, "memo": "0xab" },
+            { "name": "Id", "values": [to] }
+        ]);
+
+        assert_eq!(
+            event_accounts(&args),
+            vec![
+                (who.to_string(), String::from("0")),
+                (to.to_string(), String::from("1.to")),
+                (to.to_string(), String::from("2.0")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_event_phase() {
+        let finalization = variant("Finalization", Composite::Unnamed(vec![]));
+
+        assert_eq!(
+            event_phase(&finalization),
+            Ok((String::from("Finalization"), None))
+        );
+        assert!(event_phase(&Value::Primitive(Primitive::U32(0))).is_err());
     }
 }
@@ -0,0 +1,542 @@
+use async_trait::async_trait;
+use codec::{Decode, Encode};
+use desub_current::value::{Composite, Value};
+use sp_core::crypto::Ss58Codec;
+use sqlx::postgres::Postgres;
+use sqlx::types::{Decimal, Json};
+use sqlx::Transaction;
+
+use crate::common::BlockMetadata;
+use crate::error::{DecodeError, DecodeFailure};
+use crate::pallet_decoder::{Block, PalletDecoder};
+use crate::value_ext::ValueExt;
+
+/// An extrinsic split into the columns of `block_extrinsic`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtrinsicRow {
+    pub index: u32,
+    /// `blake2_256` of the encoded extrinsic
+    pub hash: Option<String>,
+    /// SS58 address of the signer, `None` for inherents and unsigned extrinsics
+    pub signer: Option<String>,
+    pub nonce: Option<u32>,
+    pub tip: Option<u128>,
+    /// (period, phase) of a mortal extrinsic
+    pub era: Option<(u64, u64)>,
+    pub pallet: String,
+    pub call: String,
+    /// call arguments by name, rendered like the event args
+    pub args: serde_json::Value,
+    /// from the `System.ExtrinsicSuccess` or `System.ExtrinsicFailed` event
+    pub success: Option<bool>,
+    /// from the first `Balances.Withdraw` event of the signer, `None` for unsigned extrinsics
+    pub fee: Option<u128>,
+}
+
+/// Splits the extrinsics json of a block stored by deeper-archive into rows. The signed
+/// extensions of legacy extrinsics are positional, only their signer is kept.
+pub fn extrinsic_rows(ext: &str, meta: &BlockMetadata) -> Result<Vec<ExtrinsicRow>, DecodeError> {
+    let current_err = match serde_json::from_str::<Vec<crate::CurrentExtrinsic>>(ext) {
+        Ok(extrinsics) => {
+            return Ok(extrinsics
+                .iter()
+                .enumerate()
+                .map(|(index, extrinsic)| current_row(index as u32, extrinsic, meta))
+                .collect())
+        }
+        Err(err) => err,
+    };
+    let extrinsics = crate::legacy_decoder::try_parse_extrinsics(ext)
+        .map_err(|_| DecodeError::TypeMismatch(format!("extrinsics json: {}", current_err)))?;
+    Ok(extrinsics
+        .iter()
+        .enumerate()
+        .map(|(index, extrinsic)| ExtrinsicRow {
+            index: index as u32,
+            hash: None,
+            signer: extrinsic.signer().map(|signer| signer.to_ss58check()),
+            nonce: None,
+            tip: None,
+            era: None,
+            pallet: extrinsic.legacy.call.module.clone(),
+            call: extrinsic.legacy.call.name.clone(),
+            args: serde_json::Value::Object(
+                extrinsic
+                    .legacy
+                    .call
+                    .args
+                    .iter()
+                    .map(|arg| (arg.name.clone(), arg.arg.clone()))
+                    .collect(),
+            ),
+            success: None,
+            fee: None,
+        })
+        .collect())
+}
+
+fn current_row(
+    index: u32,
+    extrinsic: &crate::CurrentExtrinsic,
+    meta: &BlockMetadata,
+) -> ExtrinsicRow {
+    let call_data = &extrinsic.current.call_data;
+    let fields = call_data.ty.fields();
+    let args = fields
+        .iter()
+        .zip(&call_data.arguments)
+        .enumerate()
+        .map(|(index, (field, arg))| {
+            let name = match field.name() {
+                Some(name) => name.clone(),
+                None => index.to_string(),
+            };
+            let arg = match meta {
+                BlockMetadata::Current(_, keys) => {
+                    crate::value_json::to_typed_json(keys.types(), field.ty().id(), arg)
+                }
+                BlockMetadata::Legacy(_) => crate::value_json::to_json(arg),
+            };
+            (name, arg)
+        })
+        .collect();
+
+    let mut row = ExtrinsicRow {
+        index,
+        hash: None,
+        signer: None,
+        nonce: None,
+        tip: None,
+        era: None,
+        pallet: call_data.pallet_name.to_string(),
+        call: call_data.ty.name().to_string(),
+        args: serde_json::Value::Object(args),
+        success: None,
+        fee: None,
+    };
+    if let Some(signature) = &extrinsic.current.signature {
+        row.signer =
+            crate::call_walker::extrinsic_signer(extrinsic).map(|signer| signer.to_ss58check());
+        for (name, value) in &signature.extensions {
+            match &**name {
+                "CheckNonce" => row.nonce = extension_value(value).and_then(|v| v.as_u32().ok()),
+                "ChargeTransactionPayment" => {
+                    row.tip = extension_value(value).and_then(|v| v.as_u128().ok())
+                }
+                "CheckMortality" | "CheckEra" => {
+                    row.era = extension_value(value).and_then(mortal_era)
+                }
+                _ => {}
+            }
+        }
+    }
+    row
+}
+
+// extensions are newtypes, e.g. `CheckNonce(#[codec(compact)] Index)`
+fn extension_value(value: &Value) -> Option<&Value> {
+    match value {
+        Value::Composite(Composite::Unnamed(values)) if values.len() == 1 => Some(&values[0]),
+        Value::Composite(_) => None,
+        other => Some(other),
+    }
+}
+
+/// Period and phase of an `Era`, `None` for `Immortal`. desub decodes the two bytes of a mortal
+/// era as the variant `Mortal<first byte>` holding the second byte.
+pub fn mortal_era(era: &Value) -> Option<(u64, u64)> {
+    let first: u64 = era
+        .variant_name()
+        .ok()?
+        .strip_prefix("Mortal")?
+        .parse()
+        .ok()?;
+    let second = match era {
+        Value::Variant(variant) => match &variant.values {
+            Composite::Unnamed(values) if values.len() == 1 => values[0].as_u64().ok()?,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    // same as `sp_runtime::generic::Era::decode`
+    let encoded = first + (second << 8);
+    let period = 2 << (encoded % (1 << 4));
+    let quantize_factor = (period >> 12).max(1);
+    let phase = (encoded >> 4) * quantize_factor;
+    Some((period, phase))
+}
+
+/// Hashes of the extrinsics of a block, from the SCALE encoded `Vec<OpaqueExtrinsic>` stored in
+/// `blocks.ext` by deeper-archive.
+pub fn extrinsic_hashes(block_ext: &[u8]) -> Result<Vec<String>, DecodeError> {
+    let extrinsics = Vec::<Vec<u8>>::decode(&mut &block_ext[..])
+        .map_err(|err| DecodeError::TypeMismatch(format!("block extrinsics: {}", err)))?;
+    Ok(extrinsics
+        .iter()
+        .map(|extrinsic| {
+            format!(
+                "0x{}",
+                hex::encode(sp_core::blake2_256(&extrinsic.encode()))
+            )
+        })
+        .collect())
+}
+
+/// Fills the success flag and the fee of the rows from the events emitted in the
+/// `ApplyExtrinsic` phase of each extrinsic. The runtime has no `TransactionFeePaid` event,
+/// the fee is the first `Balances.Withdraw` of the signer, `ChargeTransactionPayment`
+/// withdraws it before the call is dispatched.
+pub fn apply_events<'a>(
+    rows: &mut [ExtrinsicRow],
+    events: impl IntoIterator<Item = &'a Value>,
+    meta: &BlockMetadata,
+) {
+    for record in events {
+        let index = match record
+            .field("phase")
+            .and_then(crate::event_decoder::event_phase)
+        {
+            Ok((_, Some(index))) => index as usize,
+            _ => continue,
+        };
+        let row = match rows.get_mut(index) {
+            Some(row) => row,
+            None => continue,
+        };
+        match crate::event_decoder::event_variant(record) {
+            Some(("System", "ExtrinsicSuccess", _)) => row.success = Some(true),
+            Some(("System", "ExtrinsicFailed", _)) => row.success = Some(false),
+            // `Withdraw { who, amount }`
+            Some(("Balances", "Withdraw", fields)) if row.fee.is_none() => {
+                let who = fields
+                    .first()
+                    .and_then(|who| {
+                        crate::event_decoder::event_account_id(meta, "Balances", "Withdraw", 0, who)
+                            .ok()
+                    })
+                    .map(|who| who.to_ss58check());
+                if who.is_some() && who == row.signer {
+                    row.fee = fields.get(1).and_then(|amount| amount.as_u128().ok());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Writes the extrinsics of the blocks into `block_extrinsic`.
+pub struct ExtrinsicDecoder;
+
+#[async_trait]
+impl PalletDecoder for ExtrinsicDecoder {
+    /// (block_num, extrinsic)
+    type Row = (i32, ExtrinsicRow);
+
+    fn name(&self) -> &'static str {
+        "extrinsic"
+    }
+
+    fn events(&self) -> Vec<(&'static str, &'static str)> {
+        vec![
+            ("System", "ExtrinsicSuccess"),
+            ("System", "ExtrinsicFailed"),
+            ("Balances", "Withdraw"),
+        ]
+    }
+
+    // the extrinsics json has no hash, it is computed from the encoded extrinsics
+    fn encoded_extrinsics(&self) -> bool {
+        true
+    }
+
+    fn decode(&self, block: &Block, rows: &mut Vec<Self::Row>, failures: &mut Vec<DecodeFailure>) {
+        let block_num = block.block_num();
+        let mut extrinsics = match extrinsic_rows(block.extrinsics(), block.meta()) {
+            Ok(extrinsics) => extrinsics,
+            Err(err) => {
+                failures.push(DecodeFailure::block(block_num, err));
+                return;
+            }
+        };
+        if let Some(block_ext) = block.encoded_extrinsics() {
+            match extrinsic_hashes(block_ext) {
+                Ok(hashes) if hashes.len() == extrinsics.len() => {
+                    for (extrinsic, hash) in extrinsics.iter_mut().zip(hashes) {
+                        extrinsic.hash = Some(hash);
+                    }
+                }
+                Ok(hashes) => failures.push(DecodeFailure::block(
+                    block_num,
+                    DecodeError::TypeMismatch(format!(
+                        "{} encoded extrinsics for {} decoded",
+                        hashes.len(),
+                        extrinsics.len()
+                    )),
+                )),
+                Err(err) => failures.push(DecodeFailure::block(block_num, err)),
+            }
+        }
+        apply_events(
+            &mut extrinsics,
+            block.events().map(|(_, record)| record),
+            block.meta(),
+        );
+        for extrinsic in extrinsics {
+            rows.push((block_num, extrinsic));
+        }
+    }
+
+    async fn write(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        rows: Vec<Self::Row>,
+    ) -> Result<(), sqlx::Error> {
+        let mut to_insert_block_nums: Vec<i32> = vec![];
+        let mut to_insert_indexes: Vec<i32> = vec![];
+        let mut to_insert_hashes = vec![];
+        let mut to_insert_signers = vec![];
+        let mut to_insert_nonces = vec![];
+        let mut to_insert_tips = vec![];
+        let mut to_insert_era_periods = vec![];
+        let mut to_insert_era_phases = vec![];
+        let mut to_insert_pallets = vec![];
+        let mut to_insert_calls = vec![];
+        let mut to_insert_args = vec![];
+        let mut to_insert_successes = vec![];
+        let mut to_insert_fees = vec![];
+        rows.into_iter().for_each(|(block_num, extrinsic)| {
+            to_insert_block_nums.push(block_num);
+            to_insert_indexes.push(extrinsic.index as i32);
+            to_insert_hashes.push(extrinsic.hash);
+            to_insert_signers.push(extrinsic.signer);
+            to_insert_nonces.push(extrinsic.nonce.map(i64::from));
+            to_insert_tips.push(
+                extrinsic
+                    .tip
+                    .map(|tip| Decimal::from_i128_with_scale(tip as i128, 0)),
+            );
+            to_insert_era_periods.push(extrinsic.era.map(|era| era.0 as i64));
+            to_insert_era_phases.push(extrinsic.era.map(|era| era.1 as i64));
+            to_insert_pallets.push(extrinsic.pallet);
+            to_insert_calls.push(extrinsic.call);
+            to_insert_args.push(Json(extrinsic.args));
+            to_insert_successes.push(extrinsic.success);
+            to_insert_fees.push(
+                extrinsic
+                    .fee
+                    .map(|fee| Decimal::from_i128_with_scale(fee as i128, 0)),
+            );
+        });
+        sqlx::query(
+            r#"INSERT INTO block_extrinsic (block_num, extrinsic_index, hash, signer, nonce, tip,
+                era_period, era_phase, pallet, call, args, success, fee)
+            SELECT * FROM UNNEST($1::integer[], $2::integer[], $3::varchar[], $4::varchar[], $5::bigint[],
+                $6::numeric[], $7::bigint[], $8::bigint[], $9::varchar[], $10::varchar[], $11::jsonb[],
+                $12::boolean[], $13::numeric[])
+            ON CONFLICT (block_num, extrinsic_index) DO UPDATE SET hash = EXCLUDED.hash,
+                signer = EXCLUDED.signer, nonce = EXCLUDED.nonce, tip = EXCLUDED.tip,
+                era_period = EXCLUDED.era_period, era_phase = EXCLUDED.era_phase,
+                pallet = EXCLUDED.pallet, call = EXCLUDED.call, args = EXCLUDED.args,
+                success = EXCLUDED.success, fee = EXCLUDED.fee;"#,
+        )
+        .bind(&to_insert_block_nums)
+        .bind(&to_insert_indexes)
+        .bind(&to_insert_hashes)
+        .bind(&to_insert_signers)
+        .bind(&to_insert_nonces)
+        .bind(&to_insert_tips)
+        .bind(&to_insert_era_periods)
+        .bind(&to_insert_era_phases)
+        .bind(&to_insert_pallets)
+        .bind(&to_insert_calls)
+        .bind(&to_insert_args)
+        .bind(&to_insert_successes)
+        .bind(&to_insert_fees)
+        .execute(&mut *tx)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use desub_current::value::Primitive;
+    use sp_core::crypto::AccountId32;
+
+    use super::*;
+    use crate::test_util::variant;
+
+    fn row(index: u32) -> ExtrinsicRow {
+        ExtrinsicRow {
+            index,
+            hash: None,
+            signer: None,
+            nonce: None,
+            tip: None,
+            era: None,
+            pallet: String::from("Balances"),
+            call: String::from("transfer"),
+            args: serde_json::Value::Null,
+            success: None,
+            fee: None,
+        }
+    }
+
+    #[test]
+    fn test_mortal_era() {
+        let era = variant(
+            "Mortal86",
+            Composite::Unnamed(vec![Value::Primitive(Primitive::U8(7))]),
+        );
+
+        assert_eq!(mortal_era(&era), Some((128, 117)));
+        assert_eq!(
+            mortal_era(&variant("Immortal", Composite::Unnamed(vec![]))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_extrinsic_hashes() {
+        let extrinsics = vec![vec![1u8, 2, 3], vec![4u8]];
+
+        let hashes = extrinsic_hashes(&extrinsics.encode()).unwrap();
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(
+            hashes[0],
+            format!("0x{}", hex::encode(sp_core::blake2_256(&[12, 1, 2, 3])))
+        );
+        assert!(extrinsic_hashes(&[4]).is_err());
+    }
+
+    // an `EventRecord` of deeper-chain: phase, (pallet index, event index, fields), topics
+    fn encoded_record(index: u32, event: &[u8]) -> Vec<u8> {
+        let mut record = vec![0]; // Phase::ApplyExtrinsic
+        record.extend(index.encode());
+        record.extend(event);
+        record.push(0); // no topics
+        record
+    }
+
+    // `System.ExtrinsicSuccess { DispatchInfo { weight, class: Normal, pays_fee: Yes } }`
+    fn extrinsic_success() -> Vec<u8> {
+        let mut event = vec![0, 0];
+        event.extend(1000u64.encode());
+        event.extend([0, 0]);
+        event
+    }
+
+    // `System.ExtrinsicFailed { DispatchError::BadOrigin, DispatchInfo }`
+    fn extrinsic_failed() -> Vec<u8> {
+        let mut event = vec![0, 1, 2];
+        event.extend(1000u64.encode());
+        event.extend([0, 0]);
+        event
+    }
+
+    // `Balances.Withdraw { who, amount }`
+    fn withdraw(who: &AccountId32, amount: u128) -> Vec<u8> {
+        let mut event = vec![5, 8];
+        event.extend((who, amount).encode());
+        event
+    }
+
+    #[test]
+    fn test_apply_events() {
+        let signer =
+            AccountId32::from_ss58check("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
+                .unwrap();
+        let other = AccountId32::new([0x11; 32]);
+        let mut rows = vec![row(0), row(1)];
+        rows[1].signer = Some(signer.to_ss58check());
+        let records = vec![
+            // an unsigned extrinsic doesn't pay a fee
+            encoded_record(0, &withdraw(&other, 3)),
+            encoded_record(0, &extrinsic_success()),
+            encoded_record(1, &withdraw(&signer, 1500)),
+            // withdrawn by the call
+            encoded_record(1, &withdraw(&other, 7)),
+            encoded_record(1, &withdraw(&signer, 20)),
+            encoded_record(1, &extrinsic_failed()),
+            // no extrinsic 5 in the block
+            encoded_record(5, &extrinsic_success()),
+        ];
+        let mut storage_val = codec::Compact(records.len() as u32).encode();
+        records.iter().for_each(|record| storage_val.extend(record));
+        let meta = crate::common::deeper_metadata();
+        let events = crate::event_decoder::decode_event(
+            &hex::encode(crate::common::event_key()),
+            &hex::encode(storage_val),
+            &meta,
+        )
+        .unwrap();
+        assert_eq!(events.len(), 7);
+
+        apply_events(&mut rows, &events, &meta);
+        assert_eq!((rows[0].success, rows[0].fee), (Some(true), None));
+        assert_eq!((rows[1].success, rows[1].fee), (Some(false), Some(1500)));
+    }
+
+    #[test]
+    fn test_current_extrinsic_rows() {
+        let s = r##"[
+            {
+              "Current": {
+                "call_data": {
+                  "ty": {
+                    "name": "set",
+                    "index": 0,
+                    "fields": [{ "name": "now", "type": 152, "typeName": "T::Moment" }]
+                  },
+                  "arguments": [1649861160001],
+                  "pallet_name": "Timestamp"
+                },
+                "signature": null
+              }
+            },
+            {
+              "Current": {
+                "call_data": {
+                  "ty": {
+                    "name": "remark",
+                    "index": 1,
+                    "fields": [{ "name": "remark", "type": 10, "typeName": "Vec<u8>" }]
+                  },
+                  "arguments": [[1, 2]],
+                  "pallet_name": "System"
+                },
+                "signature": {
+                  "address": { "Id": "5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ" },
+                  "signature": {
+                    "Sr25519": "989bc770140c994dc5b9d1e63928b250936a8d6c374dc0e65666271e8e0df12a6a128c6872224a39a25d2f1c0777202cabfec15339de22160d3b786405a20f80"
+                  },
+                  "extensions": [
+                    ["CheckMortality", [{ "name": "Mortal86", "values": [7] }]],
+                    ["CheckNonce", [2]],
+                    ["ChargeTransactionPayment", [10]]
+                  ]
+                }
+              }
+            }
+          ]"##;
+
+        let rows = extrinsic_rows(s, &crate::common::deeper_metadata()).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            (rows[0].pallet.as_str(), rows[0].call.as_str()),
+            ("Timestamp", "set")
+        );
+        assert_eq!(rows[0].signer, None);
+        assert!(rows[0].args.get("now").is_some());
+
+        assert_eq!(
+            rows[1].signer.as_deref(),
+            Some("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
+        );
+        assert_eq!(rows[1].nonce, Some(2));
+        assert_eq!(rows[1].tip, Some(10));
+        assert_eq!(rows[1].era, Some((128, 117)));
+        assert!(rows[1].args.get("remark").is_some());
+    }
+}
@@ -0,0 +1,538 @@
+use desub_current::value::{Composite, Primitive, Value, Variant};
+use desub_json_resolver::{Modules, TypeResolver};
+use desub_legacy::decoder::{Chain, Decoder, Metadata};
+use serde::Deserialize;
+use sp_core::crypto::{AccountId32, Ss58Codec};
+
+use crate::error::DecodeError;
+
+// blocks before the runtime upgrade to metadata v14 (spec < 7) can't be decoded by desub-current,
+// their storage is decoded by desub-legacy with the polkadot type definitions and the types of
+// the deeper-chain pallets, and their extrinsics are stored by deeper-archive as
+// `{"Legacy": {...}}` json.
+
+// types of the deeper-chain pallets missing from the polkadot definitions of desub, in the
+// polkadot-js format of the definitions
+const DEEPER_TYPES: &str = include_str!("legacy_types.json");
+
+/// Legacy decoder with the metadata of one runtime version registered.
+pub struct LegacyMetadata {
+    spec: u32,
+    decoder: Decoder,
+}
+
+impl LegacyMetadata {
+    pub fn from_bytes(spec: u32, meta: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut decoder = Decoder::new(deeper_type_resolver()?, Chain::Custom("deeper".into()));
+        decoder.register_version(spec, Metadata::new(meta)?)?;
+        Ok(Self { spec, decoder })
+    }
+}
+
+/// The polkadot definitions of desub with the deeper-chain types added to the runtime types.
+fn deeper_type_resolver() -> Result<TypeResolver, Box<dyn std::error::Error>> {
+    let mut definitions: serde_json::Value =
+        serde_json::from_str(desub_json_resolver::default::DEFINITIONS)?;
+    let deeper: serde_json::Value = serde_json::from_str(DEEPER_TYPES)?;
+    let deeper_types = deeper
+        .pointer("/runtime/types")
+        .and_then(serde_json::Value::as_object)
+        .cloned();
+    let types = definitions
+        .pointer_mut("/runtime/types")
+        .and_then(serde_json::Value::as_object_mut);
+    match (types, deeper_types) {
+        (Some(types), Some(mut deeper_types)) => types.append(&mut deeper_types),
+        _ => return Err("type definitions without runtime types".into()),
+    }
+    Ok(TypeResolver::builder()
+        .modules(Modules::new(&definitions.to_string())?)
+        .build())
+}
+
+/// Decodes a storage value with the legacy decoder, the result is converted into the same
+/// `Value` shape desub-current decodes into, see `from_legacy_json`.
+pub fn decode_storage(
+    storage_key: &str,
+    storage_val: &str,
+    meta: &LegacyMetadata,
+) -> Result<Value, DecodeError> {
+    let key_bytes = hex::decode(storage_key)?;
+    let val_bytes = hex::decode(storage_val)?;
+    let storage = meta
+        .decoder
+        .decode_storage(meta.spec, (key_bytes, Some(val_bytes)))
+        .map_err(|err| DecodeError::Legacy(err.to_string()))?;
+    serde_json::to_value(storage.value())
+        .map_err(|err| DecodeError::Legacy(err.to_string()))
+        .and_then(from_legacy_json)
+}
+
+/// Converts the json of a value decoded by desub-legacy:
+/// - objects become `Composite::Named` and arrays `Composite::Unnamed`
+/// - enums rendered as `{"name": ..., "value": ...}` become variants
+/// - `0x` hex strings, i.e. byte arrays, hashes and account ids, become byte arrays
+/// - numbers become `u128`, or `i128` if negative
+/// - `null` (a `None` or a unit) becomes an empty `Composite::Unnamed`
+///
+/// Other enums are rendered as `"Variant"` or `{"Variant": value}`, which can't be told apart
+/// from a string or a struct here, see `event_record`, `event_phase` and
+/// `ValueExt::variant_name`.
+pub fn from_legacy_json(json: serde_json::Value) -> Result<Value, DecodeError> {
+    let value = match json {
+        serde_json::Value::Null => Value::Composite(Composite::Unnamed(vec![])),
+        serde_json::Value::Bool(val) => Value::Primitive(Primitive::Bool(val)),
+        serde_json::Value::Number(number) => {
+            let number = number.to_string();
+            match (number.parse::<u128>(), number.parse::<i128>()) {
+                (Ok(val), _) => Value::Primitive(Primitive::U128(val)),
+                (_, Ok(val)) => Value::Primitive(Primitive::I128(val)),
+                _ => return Err(DecodeError::Legacy(format!("unexpected number {}", number))),
+            }
+        }
+        serde_json::Value::String(val) => match val.strip_prefix("0x").map(hex::decode) {
+            Some(Ok(bytes)) => Value::Composite(Composite::Unnamed(
+                bytes
+                    .into_iter()
+                    .map(|byte| Value::Primitive(Primitive::U8(byte)))
+                    .collect(),
+            )),
+            _ => Value::Primitive(Primitive::Str(val)),
+        },
+        serde_json::Value::Array(values) => Value::Composite(Composite::Unnamed(
+            values
+                .into_iter()
+                .map(from_legacy_json)
+                .collect::<Result<_, _>>()?,
+        )),
+        serde_json::Value::Object(mut values) => {
+            // an `EnumField` of desub-legacy
+            if let (2, Some(serde_json::Value::String(name))) = (values.len(), values.get("name")) {
+                if let Some(value) = values.get("value") {
+                    return Ok(Value::Variant(Variant {
+                        name: name.clone(),
+                        values: variant_values(from_legacy_json(value.clone())?),
+                    }));
+                }
+            }
+            Value::Composite(Composite::Named(
+                values
+                    .into_iter()
+                    .map(|(name, value)| Ok((name, from_legacy_json(value)?)))
+                    .collect::<Result<_, DecodeError>>()?,
+            ))
+        }
+    };
+    Ok(value)
+}
+
+// the fields of a variant: a struct variant keeps its names, a tuple variant its items, a unit
+// variant has none
+fn variant_values(value: Value) -> Composite {
+    match value {
+        Value::Composite(values) => values,
+        value => Composite::Unnamed(vec![value]),
+    }
+}
+
+/// Reshapes a legacy `EventRecord` so its event is a `Variant(pallet, [Variant(event, fields)])`
+/// like the ones of desub-current, see `event_decoder::event_variant`. The pallet and the event
+/// may be rendered as `{"Pallet": {"Event": [fields]}}`.
+pub fn event_record(record: Value) -> Value {
+    match record {
+        Value::Composite(Composite::Named(fields)) => Value::Composite(Composite::Named(
+            fields
+                .into_iter()
+                .map(|(name, value)| match name.as_str() {
+                    "event" => (name, legacy_event(value)),
+                    _ => (name, value),
+                })
+                .collect(),
+        )),
+        other => other,
+    }
+}
+
+fn legacy_event(event: Value) -> Value {
+    let (pallet, event) = match event {
+        Value::Variant(Variant {
+            name,
+            values: Composite::Unnamed(mut values),
+        }) if values.len() == 1 => (name, values.remove(0)),
+        Value::Variant(Variant {
+            name,
+            values: Composite::Named(values),
+        }) if values.len() == 1 => (name, Value::Composite(Composite::Named(values))),
+        Value::Composite(Composite::Named(mut values)) if values.len() == 1 => values.remove(0),
+        other => return other,
+    };
+    Value::Variant(Variant {
+        name: pallet,
+        values: Composite::Unnamed(vec![legacy_variant(event)]),
+    })
+}
+
+// `{"Variant": fields}` or `"Variant"` into a variant
+fn legacy_variant(value: Value) -> Value {
+    match value {
+        Value::Composite(Composite::Named(mut values)) if values.len() == 1 => {
+            let (name, fields) = values.remove(0);
+            Value::Variant(Variant {
+                name,
+                values: variant_values(fields),
+            })
+        }
+        Value::Primitive(Primitive::Str(name)) => Value::Variant(Variant {
+            name,
+            values: Composite::Unnamed(vec![]),
+        }),
+        value => value,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LegacyExtrinsic {
+    #[serde(rename = "Legacy")]
+    pub legacy: LegacyExtrinsicInner,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LegacyExtrinsicInner {
+    pub call: LegacyCall,
+    pub signature: Option<LegacySignature>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LegacyCall {
+    pub module: String,
+    pub name: String,
+    pub args: Vec<LegacyArg>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LegacyArg {
+    pub name: String,
+    pub arg: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LegacySignature {
+    pub address: serde_json::Value,
+}
+
+impl LegacyExtrinsic {
+    pub fn is_call(&self, module: &str, names: &[&str]) -> bool {
+        self.legacy.call.module == module && names.contains(&self.legacy.call.name.as_str())
+    }
+
+    pub fn arg(&self, name: &str) -> Option<&serde_json::Value> {
+        self.legacy
+            .call
+            .args
+            .iter()
+            .find(|arg| arg.name == name)
+            .map(|arg| &arg.arg)
+    }
+
+    pub fn signer(&self) -> Option<AccountId32> {
+        self.legacy
+            .signature
+            .as_ref()
+            .and_then(|signature| json_to_account_id(&signature.address))
+    }
+}
+
+pub fn try_parse_extrinsics(ext: &str) -> Result<Vec<LegacyExtrinsic>, serde_json::Error> {
+    serde_json::from_str::<Vec<LegacyExtrinsic>>(ext)
+}
+
+/// Accepts the address shapes found in legacy json, a SS58 string or `{"Id": "5..."}`.
+pub fn json_to_account_id(val: &serde_json::Value) -> Option<AccountId32> {
+    match val {
+        serde_json::Value::String(addr) => AccountId32::from_ss58check(addr).ok(),
+        serde_json::Value::Object(obj) => obj.get("Id").and_then(json_to_account_id),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::BlockMetadata;
+    use crate::value_ext::ValueExt;
+
+    // `data/v13_metadata_test.scale` is built by `data/gen_v13_metadata.py`
+    fn legacy_metadata() -> BlockMetadata {
+        let meta = include_bytes!("../data/v13_metadata_test.scale");
+        BlockMetadata::from_bytes(6, meta).expect("valid metadata")
+    }
+
+    #[test]
+    fn test_decode_legacy_account() {
+        let addr = AccountId32::from_ss58check("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
+            .unwrap();
+        let storage_key = hex::encode(crate::common::system_account_key(addr.clone()));
+        // nonce 3, 1 consumer, 1 provider, 1 DPR free
+        let storage_val = "03000000010000000100000000000000000064a7b3b6e00d0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000";
+        let meta = legacy_metadata();
+
+        assert_eq!(
+            crate::common::map_key_account_id(&storage_key, "System", "Account", &meta),
+            Ok(addr)
+        );
+        assert_eq!(
+            crate::balance_decoder::decode_account_info(&storage_key, storage_val, &meta),
+            Ok((3, 1_000_000_000_000_000_000, 0, 0, 0))
+        );
+    }
+
+    #[test]
+    fn test_decode_legacy_credit() {
+        let addr = AccountId32::from_ss58check("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
+            .unwrap();
+        let storage_key = hex::encode(crate::common::user_credit_key(addr));
+        let storage_val = "01006400000000000000010000000000010e010000";
+
+        assert_eq!(
+            crate::credit_decoder::get_credit(&storage_key, storage_val, &legacy_metadata()),
+            Ok(crate::credit_decoder::CreditData {
+                campaign_id: Some(1),
+                credit: 100,
+                initial_credit_level: Some(1),
+                rank_in_initial_credit_level: Some(0),
+                number_of_referees: Some(0),
+                current_credit_level: Some(1),
+                reward_eras: Some(270),
+                expiration: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_legacy_delegator() {
+        let delegator =
+            AccountId32::from_ss58check("5DXga2eBuwuFykm9n6YdG2uCuK2ZqnNi1Py7hEzWPX3Cdtdz")
+                .unwrap();
+        let validator =
+            AccountId32::from_ss58check("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
+                .unwrap();
+        let storage_key = hex::encode(crate::common::staking_delegators_key(delegator));
+        // delegating to one validator, unrewarded since era 5
+        let storage_val = "40cf86fde7072801dc43df9199deb195ebeec77b6831527344bff70b799ab55504a88b59afe73f0e769e4f9d85cd40fd13f0874446f22d2ab6780f9cb89059307e010500000001";
+
+        let data = crate::delegation_decoder::get_delegator_data(
+            &storage_key,
+            storage_val,
+            &legacy_metadata(),
+        )
+        .unwrap();
+        assert_eq!(data.delegated_validators, vec![validator]);
+        assert_eq!(data.unrewarded_since, Some(5));
+        assert!(data.delegating);
+    }
+
+    #[test]
+    fn test_decode_legacy_events() {
+        let from = AccountId32::from_ss58check("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
+            .unwrap();
+        let to = AccountId32::from_ss58check("5DXga2eBuwuFykm9n6YdG2uCuK2ZqnNi1Py7hEzWPX3Cdtdz")
+            .unwrap();
+        let storage_key = hex::encode(crate::common::event_key());
+        // the timestamp inherent, then a transfer of 5 DPR and a credit update by extrinsic 1
+        let storage_val = "0c00000000000000407aa0090000000002000000010000000502a88b59afe73f0e769e4f9d85cd40fd13f0874446f22d2ab6780f9cb89059307e40cf86fde7072801dc43df9199deb195ebeec77b6831527344bff70b799ab5550000f4448291634500000000000000000000010000001400a88b59afe73f0e769e4f9d85cd40fd13f0874446f22d2ab6780f9cb89059307e780000000000000000";
+        let meta = legacy_metadata();
+
+        let events = crate::event_decoder::decode_event(&storage_key, storage_val, &meta).unwrap();
+        assert_eq!(events.len(), 3);
+
+        let success = crate::event_decoder::event_row(&events[0], &meta).unwrap();
+        assert_eq!(
+            (success.pallet.as_str(), success.variant.as_str()),
+            ("System", "ExtrinsicSuccess")
+        );
+        assert_eq!(
+            (success.phase.as_str(), success.extrinsic_index),
+            ("ApplyExtrinsic", Some(0))
+        );
+
+        let (pallet, name, fields) = crate::event_decoder::event_variant(&events[1]).unwrap();
+        assert_eq!((pallet, name), ("Balances", "Transfer"));
+        assert_eq!(fields.len(), 3);
+        assert_eq!(fields[0].as_account_id(), Ok(from.clone()));
+        assert_eq!(fields[1].as_account_id(), Ok(to));
+        assert_eq!(fields[2].as_u128(), Ok(5_000_000_000_000_000_000));
+        let transfer = crate::event_decoder::event_row(&events[1], &meta).unwrap();
+        assert_eq!(transfer.extrinsic_index, Some(1));
+
+        assert_eq!(
+            crate::credit_decoder::get_event_credits(&events, &meta),
+            vec![Ok((
+                from,
+                crate::credit_decoder::CreditData::with_credit(120)
+            ))]
+        );
+    }
+
+    #[test]
+    fn test_from_legacy_json() {
+        let json = serde_json::json!({
+            "nonce": 1,
+            "level": {"name": "One", "value": null},
+            "phase": {"ApplyExtrinsic": 2},
+            "topics": ["0x0102"],
+            "delta": -1,
+        });
+
+        let value = from_legacy_json(json).unwrap();
+        assert_eq!(value.field("nonce").and_then(|v| v.as_u32()), Ok(1));
+        assert_eq!(
+            value.field("level").and_then(|v| v.variant_name()),
+            Ok("One")
+        );
+        assert_eq!(
+            crate::event_decoder::event_phase(value.field("phase").unwrap()),
+            Ok((String::from("ApplyExtrinsic"), Some(2)))
+        );
+        assert_eq!(
+            value
+                .field("topics")
+                .and_then(|v| v.as_seq())
+                .map(|v| v.to_vec()),
+            Ok(vec![crate::test_util::bytes_value(&[1, 2])])
+        );
+        assert_eq!(
+            value.field("delta"),
+            Ok(&Value::Primitive(Primitive::I128(-1)))
+        );
+    }
+
+    #[test]
+    fn test_event_record() {
+        let record = |event: serde_json::Value| {
+            event_record(
+                from_legacy_json(serde_json::json!({
+                    "phase": "Finalization",
+                    "event": event,
+                    "topics": [],
+                }))
+                .unwrap(),
+            )
+        };
+        let expected = crate::test_util::event_record(
+            0,
+            "Staking",
+            "EraPayout",
+            Composite::Unnamed(vec![
+                Value::Primitive(Primitive::U128(7)),
+                Value::Primitive(Primitive::U128(100)),
+                Value::Primitive(Primitive::U128(10)),
+            ]),
+        );
+        let expected_event = expected.field("event").unwrap();
+
+        for event in [
+            serde_json::json!({"Staking": {"EraPayout": [7, 100, 10]}}),
+            serde_json::json!({"name": "Staking", "value": {"EraPayout": [7, 100, 10]}}),
+            serde_json::json!({"name": "Staking", "value": {"name": "EraPayout", "value": [7, 100, 10]}}),
+        ] {
+            assert_eq!(record(event).field("event"), Ok(expected_event));
+        }
+        let unit = record(serde_json::json!({"System": "CodeUpdated"}));
+        assert_eq!(
+            crate::event_decoder::event_variant(&unit).map(|(pallet, name, fields)| (
+                pallet,
+                name,
+                fields.len()
+            )),
+            Some(("System", "CodeUpdated", 0))
+        );
+    }
+
+    #[test]
+    fn test_parse_legacy_extrinsics() {
+        let s = r##"[
+        {
+          "Legacy": {
+            "call": {
+              "args": [
+                {
+                  "arg": 1639713065000,
+                  "name": "now"
+                }
+              ],
+              "name": "set",
+              "module": "Timestamp"
+            },
+            "signature": null
+          }
+        },
+        {
+          "Legacy": {
+            "call": {
+              "args": [
+                {
+                  "arg": "5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ",
+                  "name": "dest"
+                },
+                {
+                  "arg": 1000000,
+                  "name": "value"
+                }
+              ],
+              "name": "transfer",
+              "module": "Balances"
+            },
+            "signature": {
+              "extra": [
+                null,
+                null,
+                null,
+                {
+                  "Mortal": [
+                    128,
+                    119
+                  ]
+                },
+                0,
+                null,
+                0
+              ],
+              "address": "5CHu6tEdZWEnGHa928e9CfsXnL5otzRg4xGwqCscXDrcH38t",
+              "signature": {
+                "name": "Sr25519",
+                "value": "0xf2bc2aa7be0920a938aafa71b290e3a74f8d9bc7ab23aa51c5627fd8340a2556e9a027aec3e70773bf57d0585895cdb98764cd826f2e8a4f28c39cfbac50488d"
+              }
+            }
+          }
+        }
+      ]"##;
+        let extrinsics = try_parse_extrinsics(s).unwrap();
+        assert_eq!(extrinsics.len(), 2);
+
+        assert!(extrinsics[0].is_call("Timestamp", &["set"]));
+        assert_eq!(
+            extrinsics[0].arg("now").unwrap().as_u64(),
+            Some(1639713065000)
+        );
+        assert_eq!(extrinsics[0].signer(), None);
+
+        assert!(extrinsics[1].is_call("Balances", &["transfer", "transfer_keep_alive"]));
+        let dest = AccountId32::from_ss58check("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
+            .unwrap();
+        assert_eq!(
+            extrinsics[1].arg("dest").and_then(json_to_account_id),
+            Some(dest)
+        );
+        let signer =
+            AccountId32::from_ss58check("5CHu6tEdZWEnGHa928e9CfsXnL5otzRg4xGwqCscXDrcH38t")
+                .unwrap();
+        assert_eq!(extrinsics[1].signer(), Some(signer));
+    }
+
+    #[test]
+    fn test_current_extrinsics_are_not_legacy() {
+        let s = r##"[{"Current": {"call_data": {}, "signature": null}}]"##;
+
+        assert!(try_parse_extrinsics(s).is_err());
+    }
+}
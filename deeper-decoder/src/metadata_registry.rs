@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use sqlx::postgres::Postgres;
+use sqlx::Pool;
+
+use crate::common::BlockMetadata;
+use crate::error::{DecodeError, DecodeFailure};
+
+/// Parsed metadata keyed by spec version, thousands of blocks share one runtime version
+/// so each version is loaded from the `metadata` table and parsed only once.
+#[derive(Default)]
+pub struct MetadataRegistry {
+    metas: HashMap<i32, Arc<BlockMetadata>>,
+    /// spec versions whose metadata can't be parsed, with the parse error
+    invalid: HashMap<i32, String>,
+}
+
+impl MetadataRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, spec: i32) -> Option<Arc<BlockMetadata>> {
+        self.metas.get(&spec).cloned()
+    }
+
+    pub fn insert(
+        &mut self,
+        spec: i32,
+        meta: &[u8],
+    ) -> Result<Arc<BlockMetadata>, Box<dyn std::error::Error>> {
+        let meta = Arc::new(BlockMetadata::from_bytes(spec as u32, meta)?);
+        self.metas.insert(spec, meta.clone());
+        Ok(meta)
+    }
+
+    /// Spec versions in `specs` which are not loaded yet, without duplicates.
+    pub fn missing_specs(&self, specs: impl IntoIterator<Item = i32>) -> Vec<i32> {
+        let mut missing: Vec<i32> = specs
+            .into_iter()
+            .filter(|spec| !self.metas.contains_key(spec) && !self.invalid.contains_key(spec))
+            .collect();
+        missing.sort_unstable();
+        missing.dedup();
+        missing
+    }
+
+    /// Attaches the metadata of its spec version to every `(block_num, extrinsics, spec)` row,
+    /// loading the versions seen for the first time. Blocks without metadata or with metadata
+    /// which can't be parsed are skipped and added to `failures`.
+    pub async fn with_metadata(
+        &mut self,
+        pool: &Pool<Postgres>,
+        rows: Vec<(i32, String, i32)>,
+        failures: &mut Vec<DecodeFailure>,
+    ) -> Result<Vec<(i32, String, Arc<BlockMetadata>)>, Box<dyn std::error::Error>> {
+        let missing = self.missing_specs(rows.iter().map(|row| row.2));
+        if !missing.is_empty() {
+            let metas: Vec<(i32, Vec<u8>)> =
+                sqlx::query_as("select version, meta from metadata where version = Any($1);")
+                    .bind(&missing[..])
+                    .fetch_all(pool)
+                    .await?;
+            for (spec, meta) in metas {
+                // a corrupt blob only fails the blocks of its version
+                if let Err(err) = self.insert(spec, &meta) {
+                    println!("metadata of spec {} can't be parsed: {}", spec, err);
+                    self.invalid.insert(spec, err.to_string());
+                }
+            }
+        }
+
+        let mut res = vec![];
+        for (block_num, extrinsics, spec) in rows {
+            match (self.get(spec), self.invalid.get(&spec)) {
+                (Some(meta), _) => res.push((block_num, extrinsics, meta)),
+                (None, Some(err)) => failures.push(DecodeFailure::block(
+                    block_num,
+                    DecodeError::InvalidMetadata(spec, err.clone()),
+                )),
+                (None, None) => failures.push(DecodeFailure::block(
+                    block_num,
+                    DecodeError::MissingMetadata(spec),
+                )),
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_specs() {
+        let mut registry = MetadataRegistry::new();
+        let meta = include_bytes!("../data/v14_metadata_deeper.scale");
+        registry.insert(7, meta).unwrap();
+
+        assert_eq!(registry.missing_specs(vec![7, 7, 7]), Vec::<i32>::new());
+        assert_eq!(registry.missing_specs(vec![9, 7, 8, 9]), vec![8, 9]);
+    }
+
+    #[test]
+    fn test_invalid_metadata_is_not_loaded_again() {
+        let mut registry = MetadataRegistry::new();
+        assert!(registry.insert(9, &[1, 2, 3]).is_err());
+        registry.invalid.insert(9, String::from("bad metadata"));
+
+        assert_eq!(registry.missing_specs(vec![9, 10]), vec![10]);
+        assert!(registry.get(9).is_none());
+    }
+
+    #[test]
+    fn test_metadata_shared_by_spec() {
+        let mut registry = MetadataRegistry::new();
+        let meta = include_bytes!("../data/v14_metadata_deeper.scale");
+        registry.insert(7, meta).unwrap();
+
+        let first = registry.get(7).unwrap();
+        let second = registry.get(7).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(registry.get(8).is_none());
+    }
+}
@@ -0,0 +1,385 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use desub_current::value::Value;
+use rayon::prelude::*;
+use sqlx::postgres::Postgres;
+use sqlx::{Pool, Transaction};
+
+use crate::call_walker::Call;
+use crate::cli_opts::DecoderKind;
+use crate::common::BlockMetadata;
+use crate::error::{DecodeError, DecodeFailure};
+use crate::legacy_decoder::LegacyExtrinsic;
+use crate::storage_map::StorageMap;
+
+// A decoder declares the storage entries, calls and events it reads, the batch loads and
+// decodes them once for all the enabled decoders. Adding a decoder for a new pallet is
+// implementing `PalletDecoder` and adding it to `registry`.
+
+/// All the decoders, in the order they are run.
+pub fn registry() -> Vec<Box<dyn DynDecoder>> {
+    vec![
+        Box::new(crate::balance_decoder::BalanceDecoder),
+        Box::new(crate::credit_decoder::CreditDecoder),
+        Box::new(crate::event_decoder::EventDecoder),
+        Box::new(crate::delegation_decoder::DelegationDecoder),
+        Box::new(crate::timestamp_decoder::TimestampDecoder),
+        Box::new(crate::extrinsic_decoder::ExtrinsicDecoder),
+    ]
+}
+
+/// The decoders of `kinds`, in the order of `registry`.
+pub fn enabled(kinds: &[DecoderKind]) -> Vec<Box<dyn DynDecoder>> {
+    registry()
+        .into_iter()
+        .filter(|decoder| kinds.contains(&decoder.kind()))
+        .collect()
+}
+
+/// Decodes one pallet into its own tables.
+#[async_trait]
+pub trait PalletDecoder: Send + Sync {
+    /// a decoded row, written by `write`
+    type Row: Send + 'static;
+
+    /// key of the decoder in the config, `decoder_progress` and `decode_failures`
+    fn name(&self) -> &'static str;
+
+    /// hex prefixes of the storage entries read by the decoder
+    fn storage_prefixes(&self) -> Vec<String> {
+        vec![]
+    }
+
+    /// `(pallet, call)` read by the decoder, nested calls included, `*` matches any name
+    fn calls(&self) -> Vec<(&'static str, &'static str)> {
+        vec![]
+    }
+
+    /// `(pallet, event)` read by the decoder, `*` matches any name
+    fn events(&self) -> Vec<(&'static str, &'static str)> {
+        vec![]
+    }
+
+    /// whether the decoder reads the SCALE encoded extrinsics of the block
+    fn encoded_extrinsics(&self) -> bool {
+        false
+    }
+
+    /// Decodes one block, an item which can't be decoded is added to `failures` and skipped.
+    fn decode(&self, block: &Block, rows: &mut Vec<Self::Row>, failures: &mut Vec<DecodeFailure>);
+
+    /// Writes the rows of a batch, in the transaction which moves the checkpoint.
+    async fn write(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        rows: Vec<Self::Row>,
+    ) -> Result<(), sqlx::Error>;
+}
+
+/// What a decoder reads from a block.
+#[derive(Debug, Clone, Default)]
+pub struct Interest {
+    pub storage_prefixes: Vec<String>,
+    pub calls: Vec<(&'static str, &'static str)>,
+    pub events: Vec<(&'static str, &'static str)>,
+    pub encoded_extrinsics: bool,
+}
+
+fn matches(filter: &[(&str, &str)], pallet: &str, name: &str) -> bool {
+    filter
+        .iter()
+        .any(|&(p, n)| (p == "*" || p == pallet) && (n == "*" || n == name))
+}
+
+/// `PalletDecoder` with its rows type erased, to keep the decoders in one registry.
+#[async_trait]
+pub trait DynDecoder: Send + Sync {
+    fn kind(&self) -> DecoderKind;
+    fn interest(&self) -> Interest;
+    fn decode_blocks(
+        &self,
+        blocks: &[BlockData],
+        failures: &mut Vec<DecodeFailure>,
+    ) -> Box<dyn Any + Send>;
+    async fn write(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        rows: Box<dyn Any + Send>,
+    ) -> Result<(), sqlx::Error>;
+}
+
+#[async_trait]
+impl<D: PalletDecoder> DynDecoder for D {
+    fn kind(&self) -> DecoderKind {
+        DecoderKind::new(self.name())
+    }
+
+    fn interest(&self) -> Interest {
+        Interest {
+            storage_prefixes: self.storage_prefixes(),
+            calls: self.calls(),
+            events: self.events(),
+            encoded_extrinsics: self.encoded_extrinsics(),
+        }
+    }
+
+    fn decode_blocks(
+        &self,
+        blocks: &[BlockData],
+        failures: &mut Vec<DecodeFailure>,
+    ) -> Box<dyn Any + Send> {
+        let interest = self.interest();
+        let mut rows: Vec<D::Row> = vec![];
+        for data in blocks {
+            // the events or calls the decoder reads could not be decoded
+            if !interest.events.is_empty() {
+                failures.extend(data.events_failure.iter().cloned());
+            }
+            if !interest.calls.is_empty() {
+                failures.extend(data.extrinsics_failure.iter().cloned());
+            }
+            let block = Block {
+                data,
+                interest: &interest,
+            };
+            self.decode(&block, &mut rows, failures);
+        }
+        Box::new(rows)
+    }
+
+    async fn write(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        rows: Box<dyn Any + Send>,
+    ) -> Result<(), sqlx::Error> {
+        let rows = rows
+            .downcast::<Vec<D::Row>>()
+            .expect("rows are decoded by the same decoder");
+        if rows.is_empty() {
+            return Ok(());
+        }
+        PalletDecoder::write(self, tx, *rows).await
+    }
+}
+
+/// A block with its storage, events and extrinsics, decoded once and shared by the decoders.
+pub struct BlockData<'a> {
+    pub block_num: i32,
+    /// extrinsics json stored by deeper-archive
+    pub extrinsics: &'a str,
+    pub meta: &'a BlockMetadata,
+    storage: &'a StorageMap,
+    encoded_extrinsics: Option<&'a [u8]>,
+    events: Vec<Value>,
+    events_failure: Option<DecodeFailure>,
+    current: Vec<crate::CurrentExtrinsic<'a>>,
+    legacy: Vec<LegacyExtrinsic>,
+    extrinsics_failure: Option<DecodeFailure>,
+}
+
+/// `blocks.ext` of the blocks if a decoder reads the encoded extrinsics.
+pub async fn get_encoded_extrinsics(
+    pool: &Pool<Postgres>,
+    block_rows: &[(i32, String, Arc<BlockMetadata>)],
+    decoders: &[&dyn DynDecoder],
+) -> Result<HashMap<i32, Vec<u8>>, sqlx::Error> {
+    if !decoders
+        .iter()
+        .any(|decoder| decoder.interest().encoded_extrinsics)
+    {
+        return Ok(HashMap::new());
+    }
+    let block_nums: Vec<i32> = block_rows.iter().map(|row| row.0).collect();
+    let rows: Vec<(i32, Vec<u8>)> =
+        sqlx::query_as("select block_num, ext from blocks where block_num = Any($1);")
+            .bind(&block_nums)
+            .fetch_all(pool)
+            .await?;
+    Ok(rows.into_iter().collect())
+}
+
+/// Decodes the events and the extrinsics of the blocks if a decoder reads them.
+pub fn block_data<'a>(
+    block_rows: &'a [(i32, String, Arc<BlockMetadata>)],
+    storage: &'a StorageMap,
+    encoded_extrinsics: &'a HashMap<i32, Vec<u8>>,
+    decoders: &[&dyn DynDecoder],
+) -> Vec<BlockData<'a>> {
+    let interests: Vec<Interest> = decoders.iter().map(|decoder| decoder.interest()).collect();
+    let with_events = interests.iter().any(|interest| !interest.events.is_empty());
+    let with_calls = interests.iter().any(|interest| !interest.calls.is_empty());
+    let event_key = hex::encode(crate::common::event_key());
+    let event_key = event_key.as_str();
+    let decode_block = move |row: &'a (i32, String, Arc<BlockMetadata>)| {
+        let mut data = BlockData {
+            block_num: row.0,
+            extrinsics: &row.1,
+            meta: &row.2,
+            storage,
+            encoded_extrinsics: encoded_extrinsics.get(&row.0).map(Vec::as_slice),
+            events: vec![],
+            events_failure: None,
+            current: vec![],
+            legacy: vec![],
+            extrinsics_failure: None,
+        };
+        if with_calls {
+            match serde_json::from_str::<Vec<crate::CurrentExtrinsic>>(&row.1) {
+                Ok(current) => data.current = current,
+                Err(current_err) => match crate::legacy_decoder::try_parse_extrinsics(&row.1) {
+                    Ok(legacy) => data.legacy = legacy,
+                    Err(_) => {
+                        data.extrinsics_failure = Some(DecodeFailure::block(
+                            row.0,
+                            DecodeError::TypeMismatch(format!("extrinsics json: {}", current_err)),
+                        ))
+                    }
+                },
+            }
+        }
+        // the call of a multisig is only dispatched with a `Multisig.MultisigExecuted` event
+        let with_multisig = data.current.iter().any(|extrinsic| {
+            crate::call_walker::extrinsic_calls(extrinsic, &row.2, &[])
+                .iter()
+                .any(|call| call.is_call("Multisig", &["as_multi"]))
+        });
+        if with_events || with_multisig {
+            if let Some(storage_str) = storage.get(row.0, event_key) {
+                match crate::event_decoder::decode_event(event_key, storage_str, &row.2) {
+                    Ok(events) => data.events = events,
+                    Err(err) => {
+                        data.events_failure =
+                            Some(DecodeFailure::storage(row.0, event_key, storage_str, err))
+                    }
+                }
+            }
+        }
+        data
+    };
+
+    // SCALE decoding of the events is CPU bound, the blocks are split between worker threads
+    block_rows.par_iter().map(decode_block).collect()
+}
+
+/// A block as seen by one decoder, only the storage entries, calls and events it is
+/// interested in are visible.
+pub struct Block<'b, 'a> {
+    data: &'b BlockData<'a>,
+    interest: &'b Interest,
+}
+
+impl<'b, 'a> Block<'b, 'a> {
+    pub fn block_num(&self) -> i32 {
+        self.data.block_num
+    }
+
+    /// extrinsics json stored by deeper-archive
+    pub fn extrinsics(&self) -> &'a str {
+        self.data.extrinsics
+    }
+
+    pub fn meta(&self) -> &'a BlockMetadata {
+        self.data.meta
+    }
+
+    /// SCALE encoded `Vec<OpaqueExtrinsic>` of the block, see `PalletDecoder::encoded_extrinsics`
+    pub fn encoded_extrinsics(&self) -> Option<&'a [u8]> {
+        self.data.encoded_extrinsics
+    }
+
+    /// (hex key, hex value) of the storage entries written in the block, an empty value is a
+    /// removed entry
+    pub fn storage(&self) -> impl Iterator<Item = (&'b String, &'b String)> {
+        let storage: &'b StorageMap = self.data.storage;
+        let block_num = self.data.block_num;
+        let prefixes: &'b [String] = &self.interest.storage_prefixes;
+        prefixes
+            .iter()
+            .flat_map(move |prefix| storage.with_prefix(block_num, prefix))
+    }
+
+    /// (index in the block, `EventRecord`) of the events
+    pub fn events(&self) -> impl Iterator<Item = (usize, &'b Value)> {
+        let filter: &'b [(&'static str, &'static str)] = &self.interest.events;
+        let events: &'b [Value] = &self.data.events;
+        events.iter().enumerate().filter(move |(_, record)| {
+            match crate::event_decoder::event_variant(record) {
+                Some((pallet, name, _)) => matches(filter, pallet, name),
+                // kept for the decoders of every event, which report it
+                None => matches(filter, "*", "*"),
+            }
+        })
+    }
+
+    /// A failure on the `System.Events` entry of the block, e.g. for an event of an
+    /// unexpected shape.
+    pub fn event_failure(&self, err: DecodeError) -> DecodeFailure {
+        let event_key = hex::encode(crate::common::event_key());
+        match self.data.storage.get(self.data.block_num, &event_key) {
+            Some(storage_str) => {
+                DecodeFailure::storage(self.data.block_num, &event_key, storage_str, err)
+            }
+            None => DecodeFailure::block(self.data.block_num, err),
+        }
+    }
+
+    /// Calls of the extrinsics, nested calls included, with the origin they are dispatched with.
+    pub fn calls(&self) -> Vec<Call<'b>> {
+        let executed = crate::call_walker::executed_multisigs(&self.data.events, self.data.meta);
+        self.data
+            .current
+            .iter()
+            .enumerate()
+            .flat_map(|(index, extrinsic)| {
+                let executed = executed.get(&(index as u32)).map_or(&[][..], Vec::as_slice);
+                crate::call_walker::extrinsic_calls(extrinsic, self.data.meta, executed)
+            })
+            .filter(|call| matches(&self.interest.calls, call.pallet, call.name))
+            .collect()
+    }
+
+    /// Extrinsics of the blocks before the metadata v14 upgrade, nested calls are not walked.
+    pub fn legacy_calls(&self) -> impl Iterator<Item = &'b LegacyExtrinsic> {
+        let filter: &'b [(&'static str, &'static str)] = &self.interest.calls;
+        let legacy: &'b [LegacyExtrinsic] = &self.data.legacy;
+        legacy.iter().filter(move |extrinsic| {
+            matches(
+                filter,
+                &extrinsic.legacy.call.module,
+                &extrinsic.legacy.call.name,
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let filter = [("Staking", "delegate"), ("Credit", "*")];
+
+        assert!(matches(&filter, "Staking", "delegate"));
+        assert!(!matches(&filter, "Staking", "undelegate"));
+        assert!(matches(&filter, "Credit", "CreditUpdateSuccess"));
+        assert!(matches(&[("*", "*")], "System", "ExtrinsicSuccess"));
+        assert!(!matches(&[], "System", "ExtrinsicSuccess"));
+    }
+
+    #[test]
+    fn test_registry_names_are_unique() {
+        let mut names: Vec<&str> = registry()
+            .iter()
+            .map(|decoder| decoder.kind().name())
+            .collect();
+        let count = names.len();
+        names.sort_unstable();
+        names.dedup();
+
+        assert_eq!(names.len(), count);
+    }
+}
@@ -0,0 +1,36 @@
+use sqlx::postgres::Postgres;
+use sqlx::{Executor, Transaction};
+
+use crate::cli_opts::DecoderKind;
+
+/// Returns the last block fully committed by `decoder`, or `None` if it never ran.
+pub async fn get_decoder_progress<'e, E>(
+    executor: E,
+    decoder: DecoderKind,
+) -> Result<Option<i32>, sqlx::Error>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    sqlx::query_scalar("select block_num from decoder_progress where decoder = $1;")
+        .bind(decoder.name())
+        .fetch_optional(executor)
+        .await
+}
+
+/// Moves the checkpoint of `decoder` to `block_num`, must be called in the same
+/// transaction as the decoded rows so the checkpoint never gets ahead of the data.
+pub async fn update_decoder_progress(
+    tx: &mut Transaction<'_, Postgres>,
+    decoder: DecoderKind,
+    block_num: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "insert into decoder_progress(decoder, block_num, updated_at) values ($1, $2, now()) on conflict (decoder) do update set block_num = excluded.block_num, updated_at = excluded.updated_at;",
+    )
+    .bind(decoder.name())
+    .bind(block_num)
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
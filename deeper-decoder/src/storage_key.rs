@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+
+use codec::Decode;
+use desub_current::decoder;
+use desub_current::value::Value;
+use desub_current::{Metadata, TypeId};
+use frame_metadata::{RuntimeMetadata, RuntimeMetadataPrefixed, StorageEntryType, StorageHasher};
+use scale_info::{PortableRegistry, TypeDef};
+use sp_core::crypto::AccountId32;
+
+use crate::error::DecodeError;
+
+/// A storage key split into the storage item it belongs to and its map keys.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageKey {
+    pub pallet: String,
+    pub entry: String,
+    pub keys: Vec<MapKey>,
+}
+
+/// One key of a storage map, in the order of the map hashers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MapKey {
+    /// the hasher doesn't keep the key (`Blake2_128`, `Twox128`...), only its hash is known
+    Hashed(Vec<u8>),
+    /// key of a `Blake2_128Concat`, `Twox64Concat` or `Identity` hasher, with its type id
+    Value(u32, Value),
+}
+
+impl StorageKey {
+    /// Reads the `index`th map key as an account id, by its type in `types`.
+    pub fn account_id(
+        &self,
+        types: &PortableRegistry,
+        index: usize,
+    ) -> Result<AccountId32, DecodeError> {
+        match self.keys.get(index) {
+            Some(MapKey::Value(ty, value)) => crate::common::typed_account_id(types, *ty, value),
+            _ => Err(DecodeError::InvalidAccountId),
+        }
+    }
+}
+
+struct KeyEntry {
+    pallet: String,
+    entry: String,
+    hashers: Vec<(StorageHasher, TypeId)>,
+    value_ty: u32,
+}
+
+/// Storage items of a v14 metadata indexed by `twox_128(pallet) ++ twox_128(entry)`, used to
+/// decode any storage key back into its pallet, entry and map keys. Keeps the type registry
+/// to render decoded values by their types.
+pub struct StorageKeyDecoder {
+    entries: HashMap<Vec<u8>, KeyEntry>,
+    types: PortableRegistry,
+    event_record_ty: Option<u32>,
+}
+
+impl StorageKeyDecoder {
+    pub fn from_bytes(meta: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let meta = match RuntimeMetadataPrefixed::decode(&mut &meta[..])?.1 {
+            RuntimeMetadata::V14(meta) => meta,
+            _ => return Err("storage key decoding needs metadata v14".into()),
+        };
+
+        let mut entries = HashMap::new();
+        let mut event_record_ty = None;
+        for pallet in &meta.pallets {
+            let storage = match &pallet.storage {
+                Some(storage) => storage,
+                None => continue,
+            };
+            for entry in &storage.entries {
+                let value_ty = match &entry.ty {
+                    StorageEntryType::Plain(ty) => ty.id(),
+                    StorageEntryType::Map { value, .. } => value.id(),
+                };
+                let hashers = match &entry.ty {
+                    StorageEntryType::Plain(ty) => {
+                        if storage.prefix == "System" && entry.name == "Events" {
+                            // `Vec<EventRecord<Event, Hash>>`
+                            event_record_ty =
+                                match meta.types.resolve(ty.id()).map(|ty| ty.type_def()) {
+                                    Some(TypeDef::Sequence(seq)) => Some(seq.type_param().id()),
+                                    _ => None,
+                                };
+                        }
+                        vec![]
+                    }
+                    StorageEntryType::Map { hashers, key, .. } => {
+                        // maps with several hashers are keyed by a tuple, one field per hasher
+                        let key_tys = match meta.types.resolve(key.id()).map(|ty| ty.type_def()) {
+                            Some(TypeDef::Tuple(tuple)) if hashers.len() > 1 => {
+                                tuple.fields().to_vec()
+                            }
+                            _ => vec![*key],
+                        };
+                        hashers.iter().cloned().zip(key_tys).collect()
+                    }
+                };
+                entries.insert(
+                    crate::common::storage_prefix(&storage.prefix, &entry.name),
+                    KeyEntry {
+                        pallet: storage.prefix.clone(),
+                        entry: entry.name.clone(),
+                        hashers,
+                        value_ty,
+                    },
+                );
+            }
+        }
+        Ok(Self {
+            entries,
+            types: meta.types,
+            event_record_ty,
+        })
+    }
+
+    pub fn types(&self) -> &PortableRegistry {
+        &self.types
+    }
+
+    /// Type id of the values of the `pallet.entry` storage item.
+    pub fn value_type(&self, pallet: &str, entry: &str) -> Option<u32> {
+        self.entries
+            .get(&crate::common::storage_prefix(pallet, entry))
+            .map(|entry| entry.value_ty)
+    }
+
+    /// Type id of the `EventRecord` items of `System.Events`.
+    pub fn event_record_type(&self) -> Option<u32> {
+        self.event_record_ty
+    }
+
+    /// Decodes a hex storage key, the storage item is found by its 32 bytes prefix, then every
+    /// map key is read according to its hasher.
+    pub fn decode_key(
+        &self,
+        meta: &Metadata,
+        storage_key: &str,
+    ) -> Result<StorageKey, DecodeError> {
+        let key_bytes = hex::decode(storage_key)?;
+        let unknown_key = || DecodeError::UnknownStorageKey(storage_key.to_string());
+        if key_bytes.len() < 32 {
+            return Err(unknown_key());
+        }
+        let (prefix, mut rest) = key_bytes.split_at(32);
+        let entry = self.entries.get(prefix).ok_or_else(unknown_key)?;
+
+        let mut keys = vec![];
+        for (hasher, ty) in &entry.hashers {
+            let (hash_len, concat) = match hasher {
+                StorageHasher::Blake2_128 | StorageHasher::Twox128 => (16, false),
+                StorageHasher::Blake2_256 | StorageHasher::Twox256 => (32, false),
+                StorageHasher::Blake2_128Concat => (16, true),
+                StorageHasher::Twox64Concat => (8, true),
+                StorageHasher::Identity => (0, true),
+            };
+            if rest.len() < hash_len {
+                return Err(unknown_key());
+            }
+            let (hash, after_hash) = rest.split_at(hash_len);
+            rest = after_hash;
+            if concat {
+                let value = decoder::decode_value_by_id(meta, ty, &mut rest)
+                    .map_err(|err| DecodeError::TypeMismatch(format!("{:?}", err)))?;
+                keys.push(MapKey::Value(ty.id(), value));
+            } else {
+                keys.push(MapKey::Hashed(hash.to_vec()));
+            }
+        }
+        if !rest.is_empty() {
+            return Err(unknown_key());
+        }
+
+        Ok(StorageKey {
+            pallet: entry.pallet.clone(),
+            entry: entry.entry.clone(),
+            keys,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sp_core::crypto::Ss58Codec;
+
+    use super::*;
+    use crate::value_ext::ValueExt;
+
+    fn key_decoder() -> (Metadata, StorageKeyDecoder) {
+        let meta = include_bytes!("../data/v14_metadata_deeper.scale");
+        (
+            Metadata::from_bytes(meta).unwrap(),
+            StorageKeyDecoder::from_bytes(meta).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_decode_map_key() {
+        let (meta, keys) = key_decoder();
+        let addr = AccountId32::from_ss58check("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
+            .unwrap();
+        let storage_key = hex::encode(crate::common::user_credit_key(addr.clone()));
+
+        let key = keys.decode_key(&meta, &storage_key).unwrap();
+        assert_eq!(key.pallet, "Credit");
+        assert_eq!(key.entry, "UserCredit");
+        assert_eq!(key.keys.len(), 1);
+        assert_eq!(key.account_id(keys.types(), 0), Ok(addr));
+    }
+
+    fn key_value(key: &StorageKey, index: usize) -> &Value {
+        match &key.keys[index] {
+            MapKey::Value(_, value) => value,
+            MapKey::Hashed(hash) => panic!("hashed key {:?}", hash),
+        }
+    }
+
+    #[test]
+    fn test_decode_twox_64_concat_key() {
+        let (meta, keys) = key_decoder();
+        // `Staking.ErasStartSessionIndex`, keyed by the era
+        let era = 7u32.to_le_bytes();
+        let mut storage_key = crate::common::storage_prefix("Staking", "ErasStartSessionIndex");
+        storage_key.extend(sp_core::twox_64(&era));
+        storage_key.extend(era);
+
+        let key = keys.decode_key(&meta, &hex::encode(&storage_key)).unwrap();
+        assert_eq!(key.pallet, "Staking");
+        assert_eq!(key.entry, "ErasStartSessionIndex");
+        assert_eq!(key.keys.len(), 1);
+        assert_eq!(key_value(&key, 0).as_u32(), Ok(7));
+        // the key is shorter than its twox_64 hash
+        assert!(matches!(
+            keys.decode_key(&meta, &hex::encode(&storage_key[..36])),
+            Err(DecodeError::UnknownStorageKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_double_map_key() {
+        let (meta, keys) = key_decoder();
+        let addr = AccountId32::from_ss58check("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
+            .unwrap();
+        // `Staking.ErasStakers`, keyed by `(EraIndex, AccountId)` with two twox_64_concat hashers
+        let era = 270u32.to_le_bytes();
+        let account: &[u8] = addr.as_ref();
+        let mut storage_key = crate::common::storage_prefix("Staking", "ErasStakers");
+        storage_key.extend(sp_core::twox_64(&era));
+        storage_key.extend(era);
+        storage_key.extend(sp_core::twox_64(account));
+        storage_key.extend(account);
+
+        let key = keys.decode_key(&meta, &hex::encode(&storage_key)).unwrap();
+        assert_eq!(key.pallet, "Staking");
+        assert_eq!(key.entry, "ErasStakers");
+        assert_eq!(key.keys.len(), 2);
+        assert_eq!(key_value(&key, 0).as_u32(), Ok(270));
+        assert_eq!(key.account_id(keys.types(), 1), Ok(addr));
+        assert_eq!(
+            key.account_id(keys.types(), 0),
+            Err(DecodeError::InvalidAccountId)
+        );
+        // the second key is missing
+        assert!(keys
+            .decode_key(&meta, &hex::encode(&storage_key[..44]))
+            .is_err());
+    }
+
+    #[test]
+    fn test_decode_identity_key() {
+        let (meta, keys) = key_decoder();
+        // `Credit.CreditSettings`, keyed by `(CampaignId, CreditLevel)` with two identity hashers
+        let mut storage_key = crate::common::storage_prefix("Credit", "CreditSettings");
+        storage_key.extend(1u16.to_le_bytes());
+        storage_key.push(2); // CreditLevel::Two
+
+        let key = keys.decode_key(&meta, &hex::encode(&storage_key)).unwrap();
+        assert_eq!(key.pallet, "Credit");
+        assert_eq!(key.entry, "CreditSettings");
+        assert_eq!(key.keys.len(), 2);
+        assert_eq!(key_value(&key, 0).as_u16(), Ok(1));
+        assert_eq!(key_value(&key, 1).variant_name(), Ok("Two"));
+    }
+
+    #[test]
+    fn test_decode_plain_key() {
+        let (meta, keys) = key_decoder();
+        let storage_key = hex::encode(crate::common::event_key());
+
+        let key = keys.decode_key(&meta, &storage_key).unwrap();
+        assert_eq!(key.pallet, "System");
+        assert_eq!(key.entry, "Events");
+        assert!(key.keys.is_empty());
+    }
+
+    #[test]
+    fn test_decode_unknown_key() {
+        let (meta, keys) = key_decoder();
+        let storage_key = hex::encode(crate::common::event_key());
+
+        assert!(matches!(
+            keys.decode_key(&meta, "0011"),
+            Err(DecodeError::UnknownStorageKey(_))
+        ));
+        // a plain storage key followed by extra bytes
+        assert!(matches!(
+            keys.decode_key(&meta, &format!("{}00", storage_key)),
+            Err(DecodeError::UnknownStorageKey(_))
+        ));
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::HashMap;
+
+/// Storage rows of a batch indexed by block number and hex key, built once and shared by
+/// all the decoders so looking up the storage of an account doesn't scan the whole batch.
+#[derive(Debug, Default)]
+pub struct StorageMap {
+    blocks: HashMap<i32, HashMap<String, String>>,
+}
+
+impl StorageMap {
+    pub fn from_rows(rows: Vec<(i32, String, String)>) -> Self {
+        let mut blocks: HashMap<i32, HashMap<String, String>> = HashMap::new();
+        for (block_num, key, value) in rows {
+            blocks.entry(block_num).or_default().insert(key, value);
+        }
+        Self { blocks }
+    }
+
+    /// Hex encoded storage value of `key` written in `block_num`.
+    pub fn get(&self, block_num: i32, key: &str) -> Option<&String> {
+        self.blocks.get(&block_num)?.get(key)
+    }
+
+    /// Storage entries written in `block_num` whose hex key starts with `prefix`.
+    pub fn with_prefix<'a>(
+        &'a self,
+        block_num: i32,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = (&'a String, &'a String)> + 'a {
+        self.blocks
+            .get(&block_num)
+            .into_iter()
+            .flat_map(|entries| entries.iter())
+            .filter(move |(key, _)| key.starts_with(prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_storage_map_get() {
+        let storage = StorageMap::from_rows(vec![
+            (1, String::from("aa"), String::from("01")),
+            (1, String::from("bb"), String::from("02")),
+            (2, String::from("aa"), String::from("03")),
+        ]);
+
+        assert_eq!(storage.get(1, "aa"), Some(&String::from("01")));
+        assert_eq!(storage.get(2, "aa"), Some(&String::from("03")));
+        assert_eq!(storage.get(2, "bb"), None);
+    }
+
+    #[test]
+    fn test_storage_map_with_prefix() {
+        let storage = StorageMap::from_rows(vec![
+            (1, String::from("aa01"), String::from("01")),
+            (1, String::from("aa02"), String::from("02")),
+            (1, String::from("bb01"), String::from("03")),
+            (2, String::from("aa03"), String::from("04")),
+        ]);
+
+        let mut entries: Vec<_> = storage.with_prefix(1, "aa").collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec![
+                (&String::from("aa01"), &String::from("01")),
+                (&String::from("aa02"), &String::from("02"))
+            ]
+        );
+        assert_eq!(storage.with_prefix(3, "aa").count(), 0);
+    }
+}
@@ -0,0 +1,48 @@
+// values shaped like the ones desub decodes, shared by the unit tests
+
+use desub_current::value::{Composite, Primitive, Value, Variant};
+use sp_core::crypto::AccountId32;
+
+/// A `[u8; N]` or `Vec<u8>`.
+pub fn bytes_value(bytes: &[u8]) -> Value {
+    Value::Composite(Composite::Unnamed(
+        bytes
+            .iter()
+            .map(|byte| Value::Primitive(Primitive::U8(*byte)))
+            .collect(),
+    ))
+}
+
+/// An `AccountId32`, a newtype of `[u8; 32]`.
+pub fn account_value(account_id: &AccountId32) -> Value {
+    let bytes: &[u8] = account_id.as_ref();
+    Value::Composite(Composite::Unnamed(vec![bytes_value(bytes)]))
+}
+
+pub fn variant(name: &str, values: Composite) -> Value {
+    Value::Variant(Variant {
+        name: name.to_string(),
+        values,
+    })
+}
+
+/// An `EventRecord` of `pallet.name` emitted by the extrinsic `index`.
+pub fn event_record(index: u32, pallet: &str, name: &str, values: Composite) -> Value {
+    Value::Composite(Composite::Named(vec![
+        (
+            String::from("phase"),
+            variant(
+                "ApplyExtrinsic",
+                Composite::Unnamed(vec![Value::Primitive(Primitive::U32(index))]),
+            ),
+        ),
+        (
+            String::from("event"),
+            variant(pallet, Composite::Unnamed(vec![variant(name, values)])),
+        ),
+        (
+            String::from("topics"),
+            Value::Composite(Composite::Unnamed(vec![])),
+        ),
+    ]))
+}
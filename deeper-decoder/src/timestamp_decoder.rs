@@ -0,0 +1,83 @@
+use async_trait::async_trait;
+use sqlx::postgres::Postgres;
+use sqlx::types::time::OffsetDateTime;
+use sqlx::Transaction;
+
+use crate::call_walker::Origin;
+use crate::error::{DecodeError, DecodeFailure};
+use crate::pallet_decoder::{Block, PalletDecoder};
+use crate::value_ext::ValueExt;
+
+/// Writes the `Timestamp.set` inherent of the blocks into `block_timestamp`.
+pub struct TimestampDecoder;
+
+#[async_trait]
+impl PalletDecoder for TimestampDecoder {
+    /// (block_num, unix time in milliseconds)
+    type Row = (i32, u64);
+
+    fn name(&self) -> &'static str {
+        "timestamp"
+    }
+
+    fn calls(&self) -> Vec<(&'static str, &'static str)> {
+        vec![("Timestamp", "set")]
+    }
+
+    fn decode(&self, block: &Block, rows: &mut Vec<Self::Row>, failures: &mut Vec<DecodeFailure>) {
+        let block_num = block.block_num();
+        // the inherent, a `Timestamp.set` nested in a signed call fails on dispatch
+        for call in block.calls() {
+            if !call.is_call("Timestamp", &["set"]) || call.origin != Origin::None {
+                continue;
+            }
+            let now = match call.args.first() {
+                Some((_, _, now)) => now.as_u64(),
+                None => Err(DecodeError::TypeMismatch(String::from(
+                    "Timestamp.set without argument",
+                ))),
+            };
+            match now {
+                Ok(ts_ms) => rows.push((block_num, ts_ms)),
+                Err(err) => failures.push(DecodeFailure::block(block_num, err)),
+            }
+        }
+        for extrinsic in block.legacy_calls() {
+            if !extrinsic.is_call("Timestamp", &["set"]) {
+                continue;
+            }
+            match extrinsic.arg("now").and_then(|now| now.as_u64()) {
+                Some(ts_ms) => rows.push((block_num, ts_ms)),
+                None => failures.push(DecodeFailure::block(
+                    block_num,
+                    DecodeError::TypeMismatch(format!(
+                        "Timestamp.set argument {:?}",
+                        extrinsic.arg("now")
+                    )),
+                )),
+            }
+        }
+    }
+
+    async fn write(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        rows: Vec<Self::Row>,
+    ) -> Result<(), sqlx::Error> {
+        let mut to_insert_block_nums = vec![];
+        let mut to_insert_ts = vec![];
+        rows.into_iter().for_each(|value| {
+            to_insert_block_nums.push(value.0);
+            to_insert_ts.push(OffsetDateTime::from_unix_timestamp((value.1 / 1000) as i64))
+        });
+        sqlx::query(
+            "insert into block_timestamp(block_num, block_time) select * from unnest ($1, $2) on conflict (block_num) do update set block_time = excluded.block_time;",
+        )
+        .bind(to_insert_block_nums)
+        .bind(to_insert_ts)
+        .execute(&mut *tx)
+        .await?;
+
+        Ok(())
+    }
+}
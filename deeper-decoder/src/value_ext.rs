@@ -0,0 +1,169 @@
+use desub_current::value::{Composite, Primitive, Value};
+use sp_core::crypto::AccountId32;
+
+use crate::error::DecodeError;
+
+/// Typed access to decoded values by field name, so a runtime upgrade which reorders or adds
+/// fields fails with a `TypeMismatch` instead of reading the wrong field.
+pub trait ValueExt {
+    /// Field `name` of a struct.
+    fn field(&self, name: &str) -> Result<&Value, DecodeError>;
+    /// Field `name` of a struct, `None` if the struct doesn't have it.
+    fn opt_field(&self, name: &str) -> Option<&Value>;
+    /// Nested field lookup, e.g. `["data", "free"]` of an `AccountInfo`.
+    fn path(&self, path: &[&str]) -> Result<&Value, DecodeError>;
+    /// Items of a sequence or tuple.
+    fn as_seq(&self) -> Result<&[Value], DecodeError>;
+    /// Name of an enum variant.
+    fn variant_name(&self) -> Result<&str, DecodeError>;
+    fn as_bool(&self) -> Result<bool, DecodeError>;
+    /// Any unsigned integer primitive.
+    fn as_u128(&self) -> Result<u128, DecodeError>;
+    fn as_u64(&self) -> Result<u64, DecodeError>;
+    fn as_u32(&self) -> Result<u32, DecodeError>;
+    fn as_u16(&self) -> Result<u16, DecodeError>;
+    fn as_u8(&self) -> Result<u8, DecodeError>;
+    /// Account id read by its layout, for legacy values without type, see
+    /// `common::field_account_id` to read a field by its type.
+    fn as_account_id(&self) -> Result<AccountId32, DecodeError>;
+}
+
+fn mismatch(expect: &str, value: &Value) -> DecodeError {
+    DecodeError::TypeMismatch(format!("expect {}, got {:?}", expect, value))
+}
+
+fn narrow<T: TryFrom<u128>>(value: &Value, expect: &str) -> Result<T, DecodeError> {
+    T::try_from(value.as_u128()?).map_err(|_| mismatch(expect, value))
+}
+
+impl ValueExt for Value {
+    fn field(&self, name: &str) -> Result<&Value, DecodeError> {
+        self.opt_field(name)
+            .ok_or_else(|| mismatch(&format!("field {}", name), self))
+    }
+
+    fn opt_field(&self, name: &str) -> Option<&Value> {
+        match self {
+            Value::Composite(Composite::Named(fields)) => fields
+                .iter()
+                .find(|(field, _)| field == name)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn path(&self, path: &[&str]) -> Result<&Value, DecodeError> {
+        let mut value = self;
+        for name in path {
+            value = value
+                .opt_field(name)
+                .ok_or_else(|| mismatch(&format!("field {}", path.join(".")), self))?;
+        }
+        Ok(value)
+    }
+
+    fn as_seq(&self) -> Result<&[Value], DecodeError> {
+        match self {
+            Value::Composite(Composite::Unnamed(values)) => Ok(values),
+            other => Err(mismatch("sequence", other)),
+        }
+    }
+
+    fn variant_name(&self) -> Result<&str, DecodeError> {
+        match self {
+            Value::Variant(variant) => Ok(&variant.name),
+            // legacy json renders unit variants as their name
+            Value::Primitive(Primitive::Str(name)) => Ok(name),
+            other => Err(mismatch("enum variant", other)),
+        }
+    }
+
+    fn as_bool(&self) -> Result<bool, DecodeError> {
+        match self {
+            Value::Primitive(Primitive::Bool(val)) => Ok(*val),
+            other => Err(mismatch("bool", other)),
+        }
+    }
+
+    fn as_u128(&self) -> Result<u128, DecodeError> {
+        match self {
+            Value::Primitive(Primitive::U8(val)) => Ok(*val as u128),
+            Value::Primitive(Primitive::U16(val)) => Ok(*val as u128),
+            Value::Primitive(Primitive::U32(val)) => Ok(*val as u128),
+            Value::Primitive(Primitive::U64(val)) => Ok(*val as u128),
+            Value::Primitive(Primitive::U128(val)) => Ok(*val),
+            other => Err(mismatch("unsigned integer", other)),
+        }
+    }
+
+    fn as_u64(&self) -> Result<u64, DecodeError> {
+        narrow(self, "u64")
+    }
+
+    fn as_u32(&self) -> Result<u32, DecodeError> {
+        narrow(self, "u32")
+    }
+
+    fn as_u16(&self) -> Result<u16, DecodeError> {
+        narrow(self, "u16")
+    }
+
+    fn as_u8(&self) -> Result<u8, DecodeError> {
+        narrow(self, "u8")
+    }
+
+    fn as_account_id(&self) -> Result<AccountId32, DecodeError> {
+        crate::common::value_to_account_id(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account_info() -> Value {
+        let data = Value::Composite(Composite::Named(vec![
+            (
+                String::from("free"),
+                Value::Primitive(Primitive::U128(1000)),
+            ),
+            (
+                String::from("reserved"),
+                Value::Primitive(Primitive::U128(10)),
+            ),
+        ]));
+        Value::Composite(Composite::Named(vec![
+            (String::from("nonce"), Value::Primitive(Primitive::U32(2))),
+            (String::from("data"), data),
+        ]))
+    }
+
+    #[test]
+    fn test_field_by_name() {
+        let info = account_info();
+
+        assert_eq!(info.field("nonce").and_then(|v| v.as_u32()), Ok(2));
+        assert_eq!(
+            info.path(&["data", "free"]).and_then(|v| v.as_u128()),
+            Ok(1000)
+        );
+        assert!(info.opt_field("providers").is_none());
+        assert!(matches!(
+            info.path(&["data", "misc_frozen"]),
+            Err(DecodeError::TypeMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_unsigned_range() {
+        let info = account_info();
+        let free = info.path(&["data", "free"]).unwrap();
+
+        assert_eq!(free.as_u64(), Ok(1000));
+        assert!(matches!(free.as_u8(), Err(DecodeError::TypeMismatch(_))));
+        assert!(matches!(
+            info.field("data").and_then(|v| v.as_u128()),
+            Err(DecodeError::TypeMismatch(_))
+        ));
+    }
+}
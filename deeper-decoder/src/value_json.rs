@@ -0,0 +1,203 @@
+use desub_current::value::{Composite, Primitive, Value};
+use scale_info::{form::PortableForm, Field, PortableRegistry, TypeDef, TypeDefPrimitive};
+use serde_json::{json, Map};
+use sp_core::crypto::Ss58Codec;
+
+// desub serializes an account id as nested arrays of 32 numbers, which can't be filtered on
+// in sql or hasura. Values are rendered here with their type: `AccountId32` becomes a SS58
+// string (with the default ss58 prefix set from the config), byte arrays become `0x` hex and
+// newtypes are unwrapped.
+
+/// Renders `value` decoded as the type `ty` of `types`.
+pub fn to_typed_json(types: &PortableRegistry, ty_id: u32, value: &Value) -> serde_json::Value {
+    let ty = match types.resolve(ty_id) {
+        Some(ty) => ty,
+        None => return to_json(value),
+    };
+    if ty.path().segments().last().map(String::as_str) == Some("AccountId32") {
+        if let Ok(account) = crate::common::typed_account_id(types, ty_id, value) {
+            return serde_json::Value::String(account.to_ss58check());
+        }
+    }
+
+    match (ty.type_def(), value) {
+        (TypeDef::Composite(composite), Value::Composite(values)) => {
+            fields_to_json(types, composite.fields(), values)
+        }
+        (TypeDef::Variant(variants), Value::Variant(variant)) => {
+            let values = match variants
+                .variants()
+                .iter()
+                .find(|def| def.name() == &variant.name)
+            {
+                // variant values stay an array, e.g. `info->'event'->'values'->0`
+                Some(def) => match &variant.values {
+                    Composite::Unnamed(values) if def.fields().len() == values.len() => {
+                        serde_json::Value::Array(
+                            def.fields()
+                                .iter()
+                                .zip(values)
+                                .map(|(field, value)| to_typed_json(types, field.ty().id(), value))
+                                .collect(),
+                        )
+                    }
+                    values => fields_to_json(types, def.fields(), values),
+                },
+                None => composite_to_json(&variant.values),
+            };
+            json!({ "name": variant.name, "values": values })
+        }
+        (TypeDef::Sequence(seq), Value::Composite(Composite::Unnamed(values))) => {
+            items_to_json(types, seq.type_param().id(), values)
+        }
+        (TypeDef::Array(array), Value::Composite(Composite::Unnamed(values))) => {
+            items_to_json(types, array.type_param().id(), values)
+        }
+        (TypeDef::Tuple(tuple), Value::Composite(Composite::Unnamed(values)))
+            if tuple.fields().len() == values.len() =>
+        {
+            serde_json::Value::Array(
+                tuple
+                    .fields()
+                    .iter()
+                    .zip(values)
+                    .map(|(ty, value)| to_typed_json(types, ty.id(), value))
+                    .collect(),
+            )
+        }
+        (TypeDef::Compact(compact), value) => {
+            to_typed_json(types, compact.type_param().id(), value)
+        }
+        _ => to_json(value),
+    }
+}
+
+/// Renders `value` without type information, byte arrays become `0x` hex.
+pub fn to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Composite(values) => composite_to_json(values),
+        Value::Variant(variant) => {
+            json!({ "name": variant.name, "values": composite_to_json(&variant.values) })
+        }
+        other => serde_json::to_value(other).unwrap_or(serde_json::Value::Null),
+    }
+}
+
+fn fields_to_json(
+    types: &PortableRegistry,
+    fields: &[Field<PortableForm>],
+    values: &Composite,
+) -> serde_json::Value {
+    match values {
+        Composite::Named(values) if fields.len() == values.len() => serde_json::Value::Object(
+            fields
+                .iter()
+                .zip(values)
+                .map(|(field, (name, value))| {
+                    (name.clone(), to_typed_json(types, field.ty().id(), value))
+                })
+                .collect::<Map<_, _>>(),
+        ),
+        // newtype, e.g. `H256([u8; 32])`
+        Composite::Unnamed(values) if fields.len() == 1 && values.len() == 1 => {
+            to_typed_json(types, fields[0].ty().id(), &values[0])
+        }
+        Composite::Unnamed(values) if fields.len() == values.len() => serde_json::Value::Array(
+            fields
+                .iter()
+                .zip(values)
+                .map(|(field, value)| to_typed_json(types, field.ty().id(), value))
+                .collect(),
+        ),
+        other => composite_to_json(other),
+    }
+}
+
+fn items_to_json(types: &PortableRegistry, item_ty: u32, values: &[Value]) -> serde_json::Value {
+    let is_u8 = matches!(
+        types.resolve(item_ty).map(|ty| ty.type_def()),
+        Some(TypeDef::Primitive(TypeDefPrimitive::U8))
+    );
+    if is_u8 {
+        if let Some(bytes) = crate::common::value_bytes(values) {
+            return serde_json::Value::String(format!("0x{}", hex::encode(bytes)));
+        }
+    }
+    serde_json::Value::Array(
+        values
+            .iter()
+            .map(|value| to_typed_json(types, item_ty, value))
+            .collect(),
+    )
+}
+
+fn composite_to_json(values: &Composite) -> serde_json::Value {
+    match values {
+        Composite::Named(values) => serde_json::Value::Object(
+            values
+                .iter()
+                .map(|(name, value)| (name.clone(), to_json(value)))
+                .collect::<Map<_, _>>(),
+        ),
+        Composite::Unnamed(values) if !values.is_empty() && is_bytes(values) => {
+            let bytes = crate::common::value_bytes(values).unwrap_or_default();
+            serde_json::Value::String(format!("0x{}", hex::encode(bytes)))
+        }
+        Composite::Unnamed(values) => {
+            serde_json::Value::Array(values.iter().map(to_json).collect())
+        }
+    }
+}
+
+fn is_bytes(values: &[Value]) -> bool {
+    values
+        .iter()
+        .all(|value| matches!(value, Value::Primitive(Primitive::U8(_))))
+}
+
+#[cfg(test)]
+mod tests {
+    use scale_info::{meta_type, Registry};
+    use sp_core::crypto::AccountId32;
+
+    use super::*;
+    use crate::test_util::{account_value, bytes_value};
+
+    #[test]
+    fn test_typed_account_id_and_bytes() {
+        let mut registry = Registry::new();
+        let ty = registry
+            .register_type(&meta_type::<(AccountId32, Vec<u8>)>())
+            .id();
+        let types = PortableRegistry::from(registry);
+        let addr = AccountId32::from_ss58check("5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ")
+            .unwrap();
+        let value = Value::Composite(Composite::Unnamed(vec![
+            account_value(&addr),
+            bytes_value(&[1, 2]),
+        ]));
+
+        assert_eq!(
+            to_typed_json(&types, ty, &value),
+            json!(["5FshJD1E8MuZw4U2sUWLQHeKuDmkQ85MZacBA36PEJj77xAZ", "0x0102"])
+        );
+    }
+
+    #[test]
+    fn test_untyped_bytes() {
+        let value = Value::Composite(Composite::Named(vec![
+            (String::from("data"), bytes_value(&[0xab, 0xcd])),
+            (
+                String::from("amounts"),
+                Value::Composite(Composite::Unnamed(vec![Value::Primitive(Primitive::U64(
+                    300,
+                ))])),
+            ),
+        ]));
+
+        assert_eq!(
+            to_json(&value),
+            json!({ "data": "0xabcd", "amounts": [300] })
+        );
+    }
+}
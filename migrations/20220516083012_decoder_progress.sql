@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS decoder_progress (
+  decoder varchar(32) NOT NULL PRIMARY KEY,
+  block_num integer NOT NULL,
+  updated_at timestamp with time zone NOT NULL DEFAULT now()
+);
+
+-- the decoder used to resume from the last block_timestamp row, start every decoder from there
+INSERT INTO decoder_progress (decoder, block_num)
+SELECT d.decoder, ts.block_num
+FROM (VALUES ('balance'), ('credit'), ('event'), ('delegation'), ('timestamp')) AS d(decoder)
+CROSS JOIN (SELECT max(block_num) AS block_num FROM block_timestamp) AS ts
+WHERE ts.block_num IS NOT NULL
+ON CONFLICT (decoder) DO NOTHING;
@@ -0,0 +1,49 @@
+-- re-decoding a range used to insert the same rows again, keep the first copy only
+DELETE FROM block_balance a USING block_balance b
+WHERE a.block_num = b.block_num AND a.address = b.address AND a.id > b.id;
+
+DELETE FROM block_credit a USING block_credit b
+WHERE a.block_num = b.block_num AND a.address = b.address AND a.id > b.id;
+
+DELETE FROM block_timestamp a USING block_timestamp b
+WHERE a.block_num = b.block_num AND a.id > b.id;
+
+DELETE FROM block_delegation a USING block_delegation b
+WHERE a.block_num = b.block_num AND a.delegator = b.delegator AND a.id > b.id;
+
+-- events had no index within the block, re-decoding a range appended a copy of all the events
+-- of the block. A block can emit identical events, but the phases of its events only move
+-- forward, so the sequence of a block repeats itself only if it was copied. The copies start
+-- at the shortest period of the sequence of a block which divides its length.
+CREATE TEMPORARY TABLE block_event_position AS
+SELECT id, block_num, info,
+  (row_number() OVER (PARTITION BY block_num ORDER BY id) - 1)::integer AS pos,
+  (count(*) OVER (PARTITION BY block_num))::integer AS len
+FROM block_event;
+CREATE INDEX ON block_event_position (block_num, pos);
+
+DELETE FROM block_event e USING (
+  SELECT c.block_num, min(c.pos) AS period
+  FROM block_event_position f
+  JOIN block_event_position c
+    ON c.block_num = f.block_num AND c.pos > 0 AND c.len % c.pos = 0 AND c.info = f.info
+  WHERE f.pos = 0 AND NOT EXISTS (
+    SELECT 1 FROM block_event_position x
+    JOIN block_event_position y ON y.block_num = x.block_num AND y.pos = x.pos + c.pos
+    WHERE x.block_num = c.block_num AND x.info IS DISTINCT FROM y.info
+  )
+  GROUP BY c.block_num
+) copies, block_event_position p
+WHERE e.id = p.id AND p.block_num = copies.block_num AND p.pos >= copies.period;
+
+-- events are inserted in block order
+ALTER TABLE block_event ADD COLUMN event_index integer;
+UPDATE block_event e SET event_index = p.pos FROM block_event_position p WHERE e.id = p.id;
+ALTER TABLE block_event ALTER COLUMN event_index SET NOT NULL;
+DROP TABLE block_event_position;
+
+ALTER TABLE block_balance ADD CONSTRAINT block_balance_block_num_address_key UNIQUE (block_num, address);
+ALTER TABLE block_credit ADD CONSTRAINT block_credit_block_num_address_key UNIQUE (block_num, address);
+ALTER TABLE block_timestamp ADD CONSTRAINT block_timestamp_block_num_key UNIQUE (block_num);
+ALTER TABLE block_event ADD CONSTRAINT block_event_block_num_event_index_key UNIQUE (block_num, event_index);
+ALTER TABLE block_delegation ADD CONSTRAINT block_delegation_block_num_delegator_key UNIQUE (block_num, delegator);
@@ -0,0 +1,12 @@
+-- items a decoder skipped, kept until a retry decodes them
+CREATE TABLE IF NOT EXISTS decode_failures (
+  id bigserial NOT NULL PRIMARY KEY,
+  decoder varchar(32) NOT NULL,
+  block_num integer NOT NULL,
+  storage_key text,
+  storage_value text,
+  error text NOT NULL,
+  created_at timestamp with time zone NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS decode_failures_decoder_block_num_idx ON decode_failures (decoder, block_num);
@@ -0,0 +1,10 @@
+-- keep the whole CreditData of an account, credit is a u64 on chain
+ALTER TABLE block_credit
+  ALTER COLUMN credit TYPE numeric(20, 0),
+  ADD COLUMN IF NOT EXISTS campaign_id integer,
+  ADD COLUMN IF NOT EXISTS initial_credit_level smallint,
+  ADD COLUMN IF NOT EXISTS rank_in_initial_credit_level bigint,
+  ADD COLUMN IF NOT EXISTS number_of_referees smallint,
+  ADD COLUMN IF NOT EXISTS current_credit_level smallint,
+  ADD COLUMN IF NOT EXISTS reward_eras bigint,
+  ADD COLUMN IF NOT EXISTS expiration bigint;
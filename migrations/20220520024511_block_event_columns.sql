@@ -0,0 +1,39 @@
+-- pallet, variant, phase and topics of every event as columns, to join events to extrinsics
+-- without digging in `info`. The decoded events are backfilled from `info`, the event decoder
+-- is not started over.
+
+ALTER TABLE block_event
+  ADD COLUMN pallet varchar(64),
+  ADD COLUMN variant varchar(64),
+  ADD COLUMN phase varchar(16),
+  ADD COLUMN extrinsic_index bigint,
+  ADD COLUMN topics text[] NOT NULL DEFAULT '{}',
+  ADD COLUMN args jsonb;
+
+-- variants are `{"name": ..., "values": [...]}` in `info`, topics were arrays of bytes before
+-- they were rendered as `0x` hex
+UPDATE block_event SET
+  pallet = info->'event'->>'name',
+  variant = info->'event'->'values'->0->>'name',
+  phase = info->'phase'->>'name',
+  extrinsic_index = CASE WHEN info->'phase'->>'name' = 'ApplyExtrinsic'
+    THEN (info->'phase'->'values'->>0)::bigint END,
+  args = info->'event'->'values'->0->'values',
+  topics = ARRAY(
+    SELECT CASE WHEN jsonb_typeof(t.topic) = 'string' THEN t.topic #>> '{}'
+      ELSE '0x' || (
+        SELECT string_agg(lpad(to_hex(b.byte::integer), 2, '0'), '' ORDER BY b.ord)
+        FROM jsonb_path_query(t.topic, 'strict $.**') WITH ORDINALITY AS b(byte, ord)
+        WHERE jsonb_typeof(b.byte) = 'number')
+      END
+    FROM jsonb_array_elements(COALESCE(info->'topics', '[]')) WITH ORDINALITY AS t(topic, ord)
+    ORDER BY t.ord);
+
+ALTER TABLE block_event
+  ALTER COLUMN pallet SET NOT NULL,
+  ALTER COLUMN variant SET NOT NULL,
+  ALTER COLUMN phase SET NOT NULL;
+
+CREATE INDEX IF NOT EXISTS block_event_pallet_variant_idx ON block_event (pallet, variant);
+CREATE INDEX IF NOT EXISTS block_event_block_num_extrinsic_index_idx ON block_event (block_num, extrinsic_index);
+CREATE INDEX IF NOT EXISTS block_event_topics_idx ON block_event USING gin (topics);
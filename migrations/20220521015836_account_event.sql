@@ -0,0 +1,40 @@
+-- accounts found in the fields of every event, `role` is the path of the field, e.g. `who`
+CREATE TABLE IF NOT EXISTS account_event (
+  id bigserial NOT NULL PRIMARY KEY,
+  address varchar(48) NOT NULL,
+  block_num integer NOT NULL,
+  event_index integer NOT NULL,
+  role varchar(128) NOT NULL,
+  CONSTRAINT account_event_block_num_event_index_address_role_key UNIQUE (block_num, event_index, address, role)
+);
+
+CREATE INDEX IF NOT EXISTS account_event_address_block_num_idx ON account_event USING btree (address, block_num);
+
+-- the accounts of the events decoded so far, the paths are built as `event_accounts` does:
+-- variant names are skipped and variant values keep the path of the variant. Only the
+-- accounts already rendered as SS58 strings are found, events stored before they were
+-- rendered with their types get their accounts when their blocks are decoded again.
+WITH RECURSIVE fields (block_num, event_index, path, value) AS (
+  SELECT block_num, event_index, '', args FROM block_event WHERE args IS NOT NULL
+  UNION ALL
+  SELECT f.block_num, f.event_index,
+    CASE
+      WHEN c.key = 'values' AND f.value ? 'name' THEN f.path
+      WHEN f.path = '' THEN c.key
+      ELSE f.path || '.' || c.key
+    END,
+    c.value
+  FROM fields f
+  CROSS JOIN LATERAL (
+    SELECT o.key, o.value FROM jsonb_each(CASE WHEN jsonb_typeof(f.value) = 'object' THEN f.value END) o
+    UNION ALL
+    SELECT (a.ord - 1)::text, a.value
+    FROM jsonb_array_elements(CASE WHEN jsonb_typeof(f.value) = 'array' THEN f.value END) WITH ORDINALITY AS a(value, ord)
+  ) c
+  WHERE NOT (c.key = 'name' AND jsonb_typeof(f.value) = 'object' AND f.value ? 'values')
+)
+INSERT INTO account_event (block_num, event_index, address, role)
+SELECT DISTINCT block_num, event_index, value #>> '{}', path
+FROM fields
+WHERE jsonb_typeof(value) = 'string' AND value #>> '{}' ~ '^[1-9A-HJ-NP-Za-km-z]{47,48}$'
+ON CONFLICT (block_num, event_index, address, role) DO NOTHING;
@@ -0,0 +1,31 @@
+-- every extrinsic with its signer, call and outcome, `success` and `fee` come from the events
+-- of the extrinsic and are NULL when the block events are missing
+CREATE TABLE IF NOT EXISTS block_extrinsic (
+  id bigserial NOT NULL PRIMARY KEY,
+  block_num integer NOT NULL,
+  extrinsic_index integer NOT NULL,
+  hash varchar(66),
+  signer varchar(48),
+  nonce bigint,
+  tip numeric(30, 0),
+  era_period bigint,
+  era_phase bigint,
+  pallet varchar(64) NOT NULL,
+  call varchar(64) NOT NULL,
+  args jsonb,
+  success boolean,
+  fee numeric(30, 0),
+  CONSTRAINT block_extrinsic_block_num_extrinsic_index_key UNIQUE (block_num, extrinsic_index)
+);
+
+CREATE INDEX IF NOT EXISTS block_extrinsic_hash_idx ON block_extrinsic (hash);
+CREATE INDEX IF NOT EXISTS block_extrinsic_signer_block_num_idx ON block_extrinsic (signer, block_num);
+CREATE INDEX IF NOT EXISTS block_extrinsic_pallet_call_idx ON block_extrinsic (pallet, call);
+
+-- start the new decoder from the other checkpoints instead of the genesis, the sync resumes
+-- from the lowest checkpoint. Lower it to decode the extrinsics of older blocks.
+INSERT INTO decoder_progress (decoder, block_num)
+SELECT 'extrinsic', min(block_num)
+FROM decoder_progress
+HAVING min(block_num) IS NOT NULL
+ON CONFLICT (decoder) DO NOTHING;
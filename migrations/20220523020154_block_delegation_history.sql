@@ -0,0 +1,28 @@
+-- the whole `DelegatorData` of every change of a delegator, `added` and `removed` are the
+-- validators added and removed by the change.
+ALTER TABLE block_delegation
+  ADD COLUMN action varchar(16) NOT NULL DEFAULT 'update',
+  ADD COLUMN unrewarded_since bigint,
+  ADD COLUMN delegating boolean NOT NULL DEFAULT false,
+  ADD COLUMN added jsonb,
+  ADD COLUMN removed jsonb;
+
+CREATE INDEX IF NOT EXISTS block_delegation_delegator_block_num_idx ON block_delegation (delegator, block_num);
+
+-- the rows decoded so far come from `Staking.delegate` calls (undelegations were skipped), they
+-- are kept and diffed against the previous row of their delegator. `unrewarded_since` stays
+-- NULL until the blocks are decoded again, lower the `delegation` checkpoint to also add the
+-- undelegations and the era payouts.
+UPDATE block_delegation d
+SET action = CASE WHEN p.validators = '[]'::jsonb THEN 'undelegate' ELSE 'delegate' END,
+  delegating = p.validators <> '[]'::jsonb,
+  added = COALESCE((SELECT jsonb_agg(c.v ORDER BY c.i)
+      FROM jsonb_array_elements(p.validators) WITH ORDINALITY AS c(v, i)
+      WHERE NOT p.previous @> jsonb_build_array(c.v)), '[]'::jsonb),
+  removed = COALESCE((SELECT jsonb_agg(c.v ORDER BY c.i)
+      FROM jsonb_array_elements(p.previous) WITH ORDINALITY AS c(v, i)
+      WHERE NOT p.validators @> jsonb_build_array(c.v)), '[]'::jsonb)
+FROM (SELECT id, COALESCE(validators, '[]'::jsonb) AS validators,
+    COALESCE(lag(validators) OVER (PARTITION BY delegator ORDER BY block_num), '[]'::jsonb) AS previous
+  FROM block_delegation) p
+WHERE d.id = p.id;
@@ -0,0 +1,12 @@
+-- where the credit of a row was read from: the whole `Credit.UserCredit` entry ('storage'), a
+-- removed entry ('removed', credit 0 without credit data) or a `Credit` event of an account
+-- whose storage change is missing ('event', `CreditUpdateSuccess` only reports the credit).
+ALTER TABLE block_credit
+  ADD COLUMN source varchar(16) NOT NULL DEFAULT 'storage';
+
+-- the rows decoded so far can't be told apart with certainty, the rows without credit levels
+-- are the removed entries and the event credits. Lower the `credit` checkpoint to decode them
+-- again.
+UPDATE block_credit
+SET source = CASE WHEN credit = 0 THEN 'removed' ELSE 'event' END
+WHERE current_credit_level IS NULL AND initial_credit_level IS NULL;